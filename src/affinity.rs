@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::{SpawnError, VcpuCpuTime};
+
+/// Held while a VM starts its vcpus, so the vcpu threads that appear meanwhile are known to
+/// belong to it.
+static VCPU_SPAWN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Host CPUs that are online, from `/sys/devices/system/cpu/online` (e.g. `0-3,6`)
+pub(crate) fn online_cpus() -> io::Result<HashSet<usize>> {
+    let online = fs::read_to_string("/sys/devices/system/cpu/online")?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, online.trim().to_string());
+    let mut cpus = HashSet::new();
+    for range in online.trim().split(',') {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let first: usize = first.parse().map_err(|_| invalid())?;
+        let last: usize = last.parse().map_err(|_| invalid())?;
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
+
+/// Thread ids of this process's firecracker vcpu threads, mapped to their vcpu index
+fn vcpu_threads() -> io::Result<Vec<(libc::pid_t, usize)>> {
+    let mut threads = Vec::new();
+    for task in fs::read_dir("/proc/self/task")? {
+        let task = task?;
+        // Threads may exit while we look; skip them
+        let Ok(comm) = fs::read_to_string(task.path().join("comm")) else {
+            continue;
+        };
+        let Some(index) = comm.trim_end().strip_prefix("fc_vcpu ") else {
+            continue;
+        };
+        if let (Ok(tid), Ok(index)) = (task.file_name().to_string_lossy().parse(), index.parse()) {
+            threads.push((tid, index));
+        }
+    }
+    Ok(threads)
+}
+
+/// Runs `start`, which starts a VM's vcpus, and returns its result along with the vcpu
+/// threads it started, ordered by vcpu index.
+pub(crate) fn track_vcpus<T>(
+    start: impl FnOnce() -> Result<T, SpawnError>,
+) -> Result<(T, Vec<(libc::pid_t, usize)>), SpawnError> {
+    // Firecracker does not expose its vcpu threads, so they are found by name; the lock keeps
+    // other VMs from starting theirs meanwhile.
+    let _lock = VCPU_SPAWN_LOCK.lock().unwrap();
+    let existing = vcpu_threads().map_err(SpawnError::VcpuThreads)?;
+    let started = start()?;
+    let mut threads: Vec<_> = vcpu_threads()
+        .map_err(SpawnError::VcpuThreads)?
+        .into_iter()
+        .filter(|thread| !existing.contains(thread))
+        .collect();
+    threads.sort_by_key(|&(_, index)| index);
+    Ok((started, threads))
+}
+
+/// Pins each of `threads` to `affinity[vcpu index]`.
+pub(crate) fn pin_vcpus(
+    threads: &[(libc::pid_t, usize)],
+    affinity: &[usize],
+) -> Result<(), SpawnError> {
+    for &(tid, index) in threads {
+        let Some(&cpu) = affinity.get(index) else {
+            continue;
+        };
+        // SAFETY: `set` is a plain bitmask, zeroed before use.
+        let ret = unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::CPU_SET(cpu, &mut set);
+            libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+        if ret != 0 {
+            return Err(SpawnError::VcpuAffinity(io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// User and system time used by each of `threads`, leaving out those that have exited
+pub(crate) fn cpu_times(threads: &[(libc::pid_t, usize)]) -> io::Result<Vec<VcpuCpuTime>> {
+    // SAFETY: sysconf has no preconditions.
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    let ticks = |n: u64| Duration::from_nanos(n * 1_000_000_000 / ticks_per_sec);
+    let mut times = Vec::new();
+    for &(tid, vcpu) in threads {
+        let stat = match fs::read_to_string(format!("/proc/self/task/{tid}/stat")) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            stat => stat?,
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, stat.clone());
+        // The thread name, field 2, is in parentheses and contains a space; utime and stime
+        // are fields 14 and 15
+        let (_, fields) = stat.rsplit_once(')').ok_or_else(invalid)?;
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        let field = |n: usize| -> io::Result<u64> {
+            fields
+                .get(n - 3)
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(invalid)
+        };
+        times.push(VcpuCpuTime {
+            vcpu,
+            user: ticks(field(14)?),
+            system: ticks(field(15)?),
+        });
+    }
+    Ok(times)
+}
@@ -0,0 +1,107 @@
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::SpawnError;
+
+/// What firecracker's boot timer device logs, from the vcpu thread the guest wrote to it on
+const BOOT_TIME_PREFIX: &str = "Guest-boot-time =";
+
+/// Boot time cells of the running VMs, by the thread id of each of their vcpus
+static VCPUS: Mutex<Vec<(libc::pid_t, Arc<OnceLock<Duration>>)>> = Mutex::new(Vec::new());
+
+/// Installs `inner` as the `log` logger, for records up to `level`, wrapped so that the boot
+/// times firecracker's boot timer device logs are kept per VM, see
+/// [`VmHandle::boot_time`](crate::VmHandle::boot_time).
+///
+/// Firecracker only logs the boot time, so without this logger it is not kept. The `log`
+/// logger is process-wide, so this can only be called once per process, and not along with
+/// any other logger.
+pub fn init_logger(inner: Box<dyn Log>, level: LevelFilter) -> Result<(), SpawnError> {
+    log::set_boxed_logger(Box::new(BootTimeLogger { inner, level })).map_err(SpawnError::Logger)?;
+    // The boot time is logged at info level, and is kept whatever `level` is
+    log::set_max_level(level.max(LevelFilter::Info));
+    Ok(())
+}
+
+struct BootTimeLogger {
+    inner: Box<dyn Log>,
+    level: LevelFilter,
+}
+
+impl BootTimeLogger {
+    fn forwards(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && self.inner.enabled(metadata)
+    }
+}
+
+impl Log for BootTimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.forwards(metadata)
+            || (metadata.level() == Level::Info && metadata.target().starts_with("vmm"))
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Info && record.target().starts_with("vmm") {
+            record_boot_time(&record.args().to_string());
+        }
+        if self.forwards(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Keeps the boot time in `message`, if it is the boot timer's, for the VM whose vcpu is the
+/// calling thread
+pub(crate) fn record_boot_time(message: &str) {
+    let Some(rest) = message.strip_prefix(BOOT_TIME_PREFIX) else {
+        return;
+    };
+    let Some(Ok(micros)) = rest.split_whitespace().next().map(str::parse) else {
+        return;
+    };
+    // SAFETY: gettid has no preconditions.
+    let tid = unsafe { libc::gettid() };
+    let vcpus = VCPUS.lock().unwrap();
+    if let Some((_, boot_time)) = vcpus.iter().find(|(vcpu, _)| *vcpu == tid) {
+        let _ = boot_time.set(Duration::from_micros(micros));
+    }
+}
+
+/// A VM's boot time, as its boot timer device measured it. Its vcpu threads are known to the
+/// logger until this is dropped.
+pub(crate) struct BootTime {
+    value: Arc<OnceLock<Duration>>,
+    tids: Vec<libc::pid_t>,
+}
+
+impl BootTime {
+    pub(crate) fn register(vcpu_threads: &[(libc::pid_t, usize)]) -> BootTime {
+        let value = Arc::new(OnceLock::new());
+        let tids: Vec<_> = vcpu_threads.iter().map(|&(tid, _)| tid).collect();
+        VCPUS
+            .lock()
+            .unwrap()
+            .extend(tids.iter().map(|&tid| (tid, value.clone())));
+        BootTime { value, tids }
+    }
+
+    pub(crate) fn get(&self) -> Option<Duration> {
+        self.value.get().copied()
+    }
+}
+
+impl Drop for BootTime {
+    fn drop(&mut self) {
+        // Thread ids are reused once the vcpu threads have exited
+        VCPUS
+            .lock()
+            .unwrap()
+            .retain(|(tid, _)| !self.tids.contains(tid));
+    }
+}
@@ -0,0 +1,300 @@
+use std::fs::File;
+use std::time::Duration;
+
+use crate::hostnet::Net;
+use crate::{
+    BalloonConfig, CpuTemplate, Disk, EntropyConfig, HostNetwork, HugePages, KernelSource,
+    MmdsConfig, NetConfig, NetNs, OwnedNet, RebootPolicy, Seccomp, SpawnError, Tap, Vm,
+    VsockConfig,
+};
+
+/// Chained construction of a [`Vm`], validated at [`VmBuilder::build`]: everything
+/// [`Vm::validate`] checks but the host's free memory and `/dev/kvm`, which are checked when
+/// the VM starts.
+#[derive(Default)]
+pub struct VmBuilder {
+    vcpu_count: Option<u8>,
+    mem_size_mib: Option<usize>,
+    kernel: Option<KernelSource>,
+    kernel_cmdline: String,
+    cmdline_capacity: Option<usize>,
+    extra_cmdline_args: Vec<String>,
+    vsock: Option<VsockConfig>,
+    initrd: Option<File>,
+    rootfs: Option<Disk>,
+    extra_disks: Vec<Disk>,
+    net_config: Option<NetConfig>,
+    extra_nets: Vec<NetConfig>,
+    netns: Option<NetNs>,
+    owned_nets: Vec<Net>,
+    huge_pages: HugePages,
+    allow_overcommit: bool,
+    entropy: Option<EntropyConfig>,
+    balloon: Option<BalloonConfig>,
+    cpu_template: Option<CpuTemplate>,
+    smt: bool,
+    vcpu_affinity: Option<Vec<usize>>,
+    track_dirty_pages: bool,
+    mmds: Option<MmdsConfig>,
+    instance_id: Option<String>,
+    app_name: Option<String>,
+    vmm_version: Option<String>,
+    serial_banner: bool,
+    seccomp: Seccomp,
+    ready_marker: Option<String>,
+    console_tail: usize,
+    boot_timer: bool,
+    timeout: Option<Duration>,
+    reboot_policy: RebootPolicy,
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Defaults to 1
+    pub fn vcpus(mut self, vcpu_count: u8) -> Self {
+        self.vcpu_count = Some(vcpu_count);
+        self
+    }
+
+    /// Defaults to 128
+    pub fn mem_mib(mut self, mem_size_mib: usize) -> Self {
+        self.mem_size_mib = Some(mem_size_mib);
+        self
+    }
+
+    pub fn kernel(mut self, kernel: impl Into<KernelSource>) -> Self {
+        self.kernel = Some(kernel.into());
+        self
+    }
+
+    pub fn cmdline(mut self, kernel_cmdline: impl Into<String>) -> Self {
+        self.kernel_cmdline = kernel_cmdline.into();
+        self
+    }
+
+    /// Defaults to 4096 bytes
+    pub fn cmdline_capacity(mut self, capacity: usize) -> Self {
+        self.cmdline_capacity = Some(capacity);
+        self
+    }
+
+    /// Adds a param to the cmdline, e.g. `console=ttyS0` or `loglevel=7`, without replacing it
+    pub fn add_cmdline_arg(mut self, arg: impl Into<String>) -> Self {
+        self.extra_cmdline_args.push(arg.into());
+        self
+    }
+
+    pub fn rootfs(mut self, rootfs: Disk) -> Self {
+        self.rootfs = Some(rootfs);
+        self
+    }
+
+    pub fn initrd(mut self, initrd: File) -> Self {
+        self.initrd = Some(initrd);
+        self
+    }
+
+    pub fn add_disk(mut self, disk: Disk) -> Self {
+        self.extra_disks.push(disk);
+        self
+    }
+
+    /// An immutable rootfs plus a writable disk for the upper dir of an overlay on top of
+    /// it. `lower` is attached read-only as `/dev/vda` and `upper` as `/dev/vdb`, ahead of
+    /// any other disk. Mounting the overlay is up to the guest, e.g. with
+    /// [`OVERLAYROOT_PARAM`](crate::OVERLAYROOT_PARAM) on the cmdline.
+    pub fn overlay_rootfs(mut self, lower: Disk, upper: Disk) -> Self {
+        self.rootfs = Some(Disk {
+            read_only: true,
+            ..lower
+        });
+        self.extra_disks.insert(0, upper);
+        self
+    }
+
+    pub fn net(mut self, net_config: NetConfig) -> Self {
+        self.net_config = Some(net_config);
+        self
+    }
+
+    pub fn add_net(mut self, net_config: NetConfig) -> Self {
+        self.extra_nets.push(net_config);
+        self
+    }
+
+    /// Runs the VM in `netns`, see [`NetNs`]
+    pub fn netns(mut self, netns: NetNs) -> Self {
+        self.netns = Some(netns);
+        self
+    }
+
+    /// Hands `tap` over to the VM, which keeps it until the `Vm` and its handles are dropped,
+    /// see [`OwnedNet`]. Name it in a [`NetConfig`] too
+    pub fn own_tap(mut self, tap: Tap) -> Self {
+        self.owned_nets.push(Net::Tap(tap));
+        self
+    }
+
+    /// Like [`VmBuilder::own_tap`], for a [`HostNetwork`]
+    pub fn own_host_network(mut self, net: HostNetwork) -> Self {
+        self.owned_nets.push(Net::HostNetwork(net));
+        self
+    }
+
+    pub fn vsock(mut self, vsock: VsockConfig) -> Self {
+        self.vsock = Some(vsock);
+        self
+    }
+
+    /// Defaults to `HugePages::None`
+    pub fn huge_pages(mut self, huge_pages: HugePages) -> Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    /// Skips checking that the guest memory fits in what the host has available. Defaults to
+    /// `false`
+    pub fn allow_overcommit(mut self, allow: bool) -> Self {
+        self.allow_overcommit = allow;
+        self
+    }
+
+    pub fn entropy(mut self, entropy: EntropyConfig) -> Self {
+        self.entropy = Some(entropy);
+        self
+    }
+
+    pub fn balloon(mut self, balloon: BalloonConfig) -> Self {
+        self.balloon = Some(balloon);
+        self
+    }
+
+    pub fn cpu_template(mut self, cpu_template: CpuTemplate) -> Self {
+        self.cpu_template = Some(cpu_template);
+        self
+    }
+
+    pub fn smt(mut self, smt: bool) -> Self {
+        self.smt = smt;
+        self
+    }
+
+    pub fn vcpu_affinity(mut self, vcpu_affinity: Vec<usize>) -> Self {
+        self.vcpu_affinity = Some(vcpu_affinity);
+        self
+    }
+
+    pub fn track_dirty_pages(mut self, track_dirty_pages: bool) -> Self {
+        self.track_dirty_pages = track_dirty_pages;
+        self
+    }
+
+    pub fn mmds(mut self, mmds: MmdsConfig) -> Self {
+        self.mmds = Some(mmds);
+        self
+    }
+
+    /// Defaults to a random UUID
+    pub fn instance_id(mut self, instance_id: impl Into<String>) -> Self {
+        self.instance_id = Some(instance_id.into());
+        self
+    }
+
+    pub fn app_name(mut self, app_name: impl Into<String>) -> Self {
+        self.app_name = Some(app_name.into());
+        self
+    }
+
+    pub fn vmm_version(mut self, vmm_version: impl Into<String>) -> Self {
+        self.vmm_version = Some(vmm_version.into());
+        self
+    }
+
+    pub fn serial_banner(mut self, serial_banner: bool) -> Self {
+        self.serial_banner = serial_banner;
+        self
+    }
+
+    /// Defaults to firecracker's own filters
+    pub fn seccomp(mut self, seccomp: Seccomp) -> Self {
+        self.seccomp = seccomp;
+        self
+    }
+
+    /// See [`VmHandle::wait_ready`](crate::VmHandle::wait_ready)
+    pub fn ready_marker(mut self, marker: impl Into<String>) -> Self {
+        self.ready_marker = Some(marker.into());
+        self
+    }
+
+    /// Keeps the last `lines` console lines, see
+    /// [`VmHandle::console_tail`](crate::VmHandle::console_tail). Defaults to 0
+    pub fn console_tail(mut self, lines: usize) -> Self {
+        self.console_tail = lines;
+        self
+    }
+
+    pub fn boot_timer(mut self, boot_timer: bool) -> Self {
+        self.boot_timer = boot_timer;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Defaults to `RebootPolicy::Exit`
+    pub fn reboot_policy(mut self, reboot_policy: RebootPolicy) -> Self {
+        self.reboot_policy = reboot_policy;
+        self
+    }
+
+    pub fn build(self) -> Result<Vm, SpawnError> {
+        let kernel = self.kernel.ok_or(SpawnError::MissingKernel)?;
+        let vm = Vm {
+            vcpu_count: self.vcpu_count.unwrap_or(1),
+            mem_size_mib: self.mem_size_mib.unwrap_or(128),
+            kernel,
+            kernel_cmdline: self.kernel_cmdline,
+            cmdline_capacity: self.cmdline_capacity,
+            extra_cmdline_args: self.extra_cmdline_args,
+            vsock: self.vsock,
+            initrd: self.initrd,
+            rootfs: self.rootfs,
+            extra_disks: self.extra_disks,
+            net_config: self.net_config,
+            extra_nets: self.extra_nets,
+            owned_nets: self
+                .owned_nets
+                .into_iter()
+                .map(|net| OwnedNet::new(net, self.netns.clone()))
+                .collect(),
+            netns: self.netns,
+            huge_pages: self.huge_pages,
+            allow_overcommit: self.allow_overcommit,
+            entropy: self.entropy,
+            balloon: self.balloon,
+            cpu_template: self.cpu_template,
+            smt: self.smt,
+            vcpu_affinity: self.vcpu_affinity,
+            track_dirty_pages: self.track_dirty_pages,
+            mmds: self.mmds,
+            instance_id: self.instance_id,
+            app_name: self.app_name,
+            vmm_version: self.vmm_version,
+            serial_banner: self.serial_banner,
+            seccomp: self.seccomp,
+            ready_marker: self.ready_marker,
+            console_tail: self.console_tail,
+            boot_timer: self.boot_timer,
+            timeout: self.timeout,
+            reboot_policy: self.reboot_policy,
+        };
+        vm.validate_config()?;
+        Ok(vm)
+    }
+}
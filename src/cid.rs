@@ -0,0 +1,39 @@
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+use crate::SpawnError;
+
+/// Lowest CID a guest can have; 0 to 2 are reserved for the hypervisor, local and host
+pub(crate) const FIRST_GUEST_CID: u32 = 3;
+
+/// CIDs of the VMs started by this process that are still running
+static IN_USE: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+/// A guest CID reserved for one VM, released when dropped
+pub(crate) struct CidLease(u32);
+
+impl CidLease {
+    /// Reserves `requested`, or the lowest free CID if `None`.
+    pub(crate) fn acquire(requested: Option<u32>) -> Result<CidLease, SpawnError> {
+        let mut in_use = IN_USE.lock().unwrap();
+        let cid = match requested {
+            Some(cid) if in_use.contains(&cid) => return Err(SpawnError::CidInUse { cid }),
+            Some(cid) => cid,
+            None => (FIRST_GUEST_CID..u32::MAX)
+                .find(|cid| !in_use.contains(cid))
+                .expect("every guest CID is in use"),
+        };
+        in_use.insert(cid);
+        Ok(CidLease(cid))
+    }
+
+    pub(crate) fn cid(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Drop for CidLease {
+    fn drop(&mut self) {
+        IN_USE.lock().unwrap().remove(&self.0);
+    }
+}
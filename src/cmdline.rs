@@ -0,0 +1,95 @@
+use std::net::Ipv4Addr;
+
+use linux_loader::cmdline::Cmdline;
+
+use crate::{SpawnError, DEFAULT_CMDLINE_CAPACITY};
+
+/// Assembles a kernel command line from `key=value` params and bare flags, for
+/// [`VmBuilder::cmdline`](crate::VmBuilder::cmdline).
+#[derive(Clone, Default)]
+pub struct CmdlineBuilder {
+    params: Vec<(String, Option<String>)>,
+    capacity: Option<usize>,
+}
+
+impl CmdlineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn param(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.params.push((key.into(), Some(value.into())));
+        self
+    }
+
+    pub fn flag(mut self, flag: impl Into<String>) -> Self {
+        self.params.push((flag.into(), None));
+        self
+    }
+
+    /// Adds the `ip=` param that configures the guest interface `iface` (e.g. `eth0` for the
+    /// first [`NetConfig`](crate::NetConfig)) with a static address at boot, no DHCP. The
+    /// guest kernel needs `CONFIG_IP_PNP`. The kernel only honours one `ip=`, and has no
+    /// IPv6 equivalent.
+    pub fn static_ip(
+        self,
+        iface: &str,
+        addr: Ipv4Addr,
+        gateway: Ipv4Addr,
+        netmask: Ipv4Addr,
+    ) -> Self {
+        // <client-ip>:<server-ip>:<gw-ip>:<netmask>:<hostname>:<device>:<autoconf>
+        self.param("ip", format!("{addr}::{gateway}:{netmask}::{iface}:off"))
+    }
+
+    /// Maximum length in bytes, including the trailing NUL. Defaults to 4096
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Renders the command line, failing if a param is malformed or it does not fit
+    pub fn build(&self) -> Result<String, SpawnError> {
+        let mut cmdline = Cmdline::new(self.capacity.unwrap_or(DEFAULT_CMDLINE_CAPACITY))?;
+        for (key, value) in &self.params {
+            match value {
+                Some(value) => cmdline.insert(key, value)?,
+                None => cmdline.insert_str(key)?,
+            }
+        }
+        Ok(cmdline.as_cstring()?.to_string_lossy().into_owned())
+    }
+}
+
+/// The params of `cmdline` before init's arguments, split on whitespace outside double quotes
+/// like the kernel does, and the byte offset where init's arguments start: the `--` param,
+/// or the end of `cmdline`.
+pub(crate) fn boot_params(cmdline: &str) -> (Vec<&str>, usize) {
+    let mut params = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    for (i, c) in cmdline.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        match (start, c.is_whitespace() && !quoted) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                if &cmdline[s..i] == "--" {
+                    return (params, s);
+                }
+                params.push(&cmdline[s..i]);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    match start {
+        Some(s) if &cmdline[s..] == "--" => (params, s),
+        Some(s) => {
+            params.push(&cmdline[s..]);
+            (params, cmdline.len())
+        }
+        None => (params, cmdline.len()),
+    }
+}
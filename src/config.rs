@@ -0,0 +1,154 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use vmm::vmm_config::balloon::BalloonDeviceConfig;
+use vmm::vmm_config::boot_source::{BootSourceConfig, DEFAULT_KERNEL_CMDLINE};
+use vmm::vmm_config::drive::BlockDeviceConfig;
+use vmm::vmm_config::entropy::EntropyDeviceConfig;
+use vmm::vmm_config::machine_config::{HugePageConfig, MachineConfig};
+use vmm::vmm_config::mmds::MmdsConfig as VmmMmdsConfig;
+use vmm::vmm_config::net::NetworkInterfaceConfig;
+use vmm::vmm_config::vsock::VsockDeviceConfig;
+
+use crate::{
+    BalloonConfig, CpuTemplate, Disk, EntropyConfig, HugePages, MmdsConfig, NetConfig, SpawnError,
+    Vm, VmBuilder, VsockConfig,
+};
+
+/// The sections of firecracker's `--config-file` this crate can express
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(rename = "boot-source")]
+    boot_source: BootSourceConfig,
+    #[serde(default)]
+    drives: Vec<BlockDeviceConfig>,
+    #[serde(rename = "machine-config")]
+    machine_config: Option<MachineConfig>,
+    #[serde(rename = "network-interfaces", default)]
+    network_interfaces: Vec<NetworkInterfaceConfig>,
+    vsock: Option<VsockDeviceConfig>,
+    entropy: Option<EntropyDeviceConfig>,
+    balloon: Option<BalloonDeviceConfig>,
+    #[serde(rename = "mmds-config")]
+    mmds_config: Option<VmmMmdsConfig>,
+}
+
+pub(crate) fn load(path: &Path) -> Result<Vm, SpawnError> {
+    let json = std::fs::read(path).map_err(|source| SpawnError::ConfigFile {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let config: ConfigFile =
+        serde_json::from_slice(&json).map_err(|source| SpawnError::ConfigParse {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    let unsupported = |field: &str| SpawnError::ConfigUnsupported {
+        field: field.to_string(),
+    };
+
+    let boot = config.boot_source;
+    let mut builder = VmBuilder::new()
+        .kernel(PathBuf::from(boot.kernel_image_path))
+        .cmdline(boot.boot_args.as_deref().unwrap_or(DEFAULT_KERNEL_CMDLINE));
+    if let Some(initrd_path) = boot.initrd_path {
+        builder = builder.initrd(File::open(initrd_path).map_err(SpawnError::Initrd)?);
+    }
+
+    if let Some(machine) = config.machine_config {
+        builder = builder
+            .vcpus(machine.vcpu_count)
+            .mem_mib(machine.mem_size_mib)
+            .smt(machine.smt)
+            .track_dirty_pages(machine.track_dirty_pages)
+            .huge_pages(match machine.huge_pages {
+                HugePageConfig::None => HugePages::None,
+                HugePageConfig::Hugetlbfs2M => HugePages::Hugetlbfs2M,
+            });
+        if let Some(template) = machine.cpu_template {
+            // Static templates are named by their serialized form, e.g. "T2"
+            let name = serde_json::to_value(template)
+                .ok()
+                .and_then(|name| name.as_str().map(str::to_string))
+                .ok_or_else(|| unsupported("machine-config.cpu_template"))?;
+            builder = builder.cpu_template(CpuTemplate::Static(name));
+        }
+    }
+
+    let mut has_rootfs = false;
+    for drive in config.drives {
+        // Further root devices are kept as such, for `Vm::validate` to reject
+        let disk = Disk {
+            path: drive.path_on_host.map(PathBuf::from).unwrap_or_default(),
+            read_only: drive.is_read_only.unwrap_or(false),
+            cache_type: Some(drive.cache_type),
+            rate_limiter: drive.rate_limiter.map(Into::into),
+            file_engine_type: drive.file_engine_type,
+            partuuid: drive.partuuid,
+            socket: drive.socket.map(PathBuf::from),
+            is_root: drive.is_root_device,
+        };
+        if drive.is_root_device && !has_rootfs {
+            has_rootfs = true;
+            builder = builder.rootfs(disk);
+        } else {
+            builder = builder.add_disk(disk);
+        }
+    }
+
+    let mmds_ifaces = config
+        .mmds_config
+        .as_ref()
+        .map(|mmds| mmds.network_interfaces.clone())
+        .unwrap_or_default();
+    for (i, iface) in config.network_interfaces.into_iter().enumerate() {
+        let vm_mac = match iface.guest_mac {
+            Some(mac) => Some(
+                mac.get_bytes()
+                    .try_into()
+                    .map_err(|_| unsupported("network-interfaces.guest_mac"))?,
+            ),
+            None => None,
+        };
+        let net = NetConfig {
+            allow_mmds: mmds_ifaces.contains(&iface.iface_id),
+            tap_iface_name: iface.host_dev_name,
+            vm_mac,
+            rx_rate_limiter: iface.rx_rate_limiter.map(Into::into),
+            tx_rate_limiter: iface.tx_rate_limiter.map(Into::into),
+        };
+        builder = match i {
+            0 => builder.net(net),
+            _ => builder.add_net(net),
+        };
+    }
+
+    if let Some(vsock) = config.vsock {
+        builder = builder.vsock(VsockConfig {
+            uds_path: vsock.uds_path,
+            guest_cid: Some(vsock.guest_cid),
+        });
+    }
+    if let Some(entropy) = config.entropy {
+        builder = builder.entropy(EntropyConfig {
+            rate_limiter: entropy.rate_limiter.map(Into::into),
+        });
+    }
+    if let Some(balloon) = config.balloon {
+        builder = builder.balloon(BalloonConfig {
+            amount_mib: balloon.amount_mib,
+            deflate_on_oom: balloon.deflate_on_oom,
+            stats_polling_interval_s: balloon.stats_polling_interval_s,
+        });
+    }
+    if let Some(mmds) = config.mmds_config {
+        builder = builder.mmds(MmdsConfig {
+            version: mmds.version,
+            ipv4_address: mmds.ipv4_address,
+            data: None,
+        });
+    }
+    builder.build()
+}
@@ -0,0 +1,508 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+// Re-exported from the crate root, so callers can match on the sources of `SpawnError`
+// without depending on the vmm crate themselves.
+pub use event_manager::Error as EventManagerError;
+pub use linux_loader::cmdline::Error as CmdlineError;
+pub use seccompiler::InstallationError as SeccompInstallError;
+pub use vmm::builder::StartMicrovmError;
+pub use vmm::devices::virtio::balloon::BalloonError;
+pub use vmm::logger::MetricsError;
+pub use vmm::mmds::data_store::MmdsDatastoreError;
+pub use vmm::persist::{CreateSnapshotError, RestoreFromSnapshotError};
+pub use vmm::seccomp_filters::FilterError as SeccompFilterError;
+pub use vmm::vmm_config::balloon::BalloonConfigError;
+pub use vmm::vmm_config::drive::DriveError;
+pub use vmm::vmm_config::entropy::EntropyDeviceError;
+pub use vmm::vmm_config::mmds::MmdsConfigError;
+pub use vmm::vmm_config::net::NetworkInterfaceError;
+pub use vmm::vmm_config::vsock::VsockConfigError;
+pub use vmm::vstate::memory::GuestMemoryError;
+pub use vmm::VmmError;
+
+#[derive(Debug)]
+pub enum SpawnError {
+    /// No kernel was configured
+    MissingKernel,
+    /// The kernel image could not be used. `path` is set when it was given as a path.
+    Kernel {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// The initrd image could not be used
+    Initrd(io::Error),
+    /// The kernel command line was rejected
+    Cmdline(CmdlineError),
+    /// The kernel command line does not fit in its buffer
+    CmdlineTooLong { len: usize, capacity: usize },
+    /// An extra cmdline arg contains a newline or NUL
+    InvalidCmdlineArg { arg: String },
+    /// The kernel cmdline has `flag`, `ro` or `rw`, but the root disk is attached the other
+    /// way
+    RootFlagConflict { flag: String },
+    /// A block device could not be attached
+    Disk { id: String, source: DriveError },
+    /// A network interface could not be attached
+    Net {
+        iface_id: String,
+        source: NetworkInterfaceError,
+    },
+    /// The vsock device could not be attached
+    Vsock(VsockConfigError),
+    /// The entropy device could not be attached
+    Entropy(EntropyDeviceError),
+    /// The balloon device could not be attached
+    BalloonConfig(BalloonConfigError),
+    /// The balloon device rejected a runtime operation
+    Balloon(BalloonError),
+    /// The guest did not give the balloon its target size in time
+    BalloonTimeout { target_mib: u32, actual_mib: u32 },
+    /// MMDS could not be configured
+    Mmds(MmdsConfigError),
+    /// The MMDS data store rejected its initial contents
+    MmdsData(MmdsDatastoreError),
+    /// MMDS data must be a JSON object at `key`, `/` being the root
+    MmdsNotObject { key: String },
+    /// MMDS is configured but no network interface sets `allow_mmds`
+    MmdsNotAllowed,
+    /// The MMDS user-data file could not be read
+    MmdsUserData { path: PathBuf, source: io::Error },
+    /// The named static CPU template does not exist for this architecture
+    UnknownCpuTemplate { name: String },
+    /// A custom CPU template file could not be read
+    CpuTemplateFile { path: PathBuf, source: io::Error },
+    /// A custom CPU template file is not a valid template
+    CpuTemplateParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// A VM config file could not be read
+    ConfigFile { path: PathBuf, source: io::Error },
+    /// A VM config file is not valid firecracker JSON
+    ConfigParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// A VM config file uses a firecracker option this crate cannot express
+    ConfigUnsupported { field: String },
+    /// A custom seccomp filter file could not be read
+    SeccompFile { path: PathBuf, source: io::Error },
+    /// The seccomp filters could not be loaded
+    Seccomp(SeccompFilterError),
+    /// The event loop thread could not install its seccomp filter
+    SeccompApply(SeccompInstallError),
+    /// Neither a rootfs nor an initrd was configured, and the cmdline sets no `root=` or `init=`
+    NoBootDevice,
+    /// More than one disk is the root device
+    MultipleRootDisks,
+    /// A disk must have exactly one of `path` and `socket`
+    DiskBackend { id: String },
+    /// The backing file of a disk does not exist
+    DiskNotFound { id: String, path: PathBuf },
+    /// The VM has no block device with this id
+    UnknownDrive { id: String },
+    /// A MAC address is not six colon-separated hex octets
+    InvalidMac { mac: String },
+    /// A network interface has no TAP device name
+    EmptyTapName,
+    /// There is no TAP interface with this name on the host
+    TapNotFound { name: String },
+    /// Another process is already attached to this TAP interface
+    TapBusy { name: String },
+    /// Guest CIDs 0 to 2 and `u32::MAX` are reserved
+    ReservedCid { cid: u32 },
+    /// Another VM started by this process has this guest CID
+    CidInUse { cid: u32 },
+    /// The VM needs at least one vcpu, and at most `max`
+    InvalidVcpuCount { requested: u8, max: u8 },
+    /// SMT is only supported on x86_64
+    SmtNotSupported,
+    /// With SMT enabled the vcpu count must be 1 or even
+    OddVcpuCountWithSmt { requested: u8 },
+    /// `vcpu_affinity` must list one host CPU per vcpu
+    VcpuAffinityLength { len: usize, vcpu_count: u8 },
+    /// A host CPU in `vcpu_affinity` is not online
+    CpuOffline { cpu: usize },
+    /// The vcpu threads could not be pinned to their host CPUs
+    VcpuAffinity(io::Error),
+    /// The vcpu threads could not be found or inspected in `/proc/self/task`
+    VcpuThreads(io::Error),
+    /// The bundled firecracker cannot back guest memory with pages of this size
+    UnsupportedHugePages { page_size_mib: usize },
+    /// The VM needs some memory
+    ZeroMemSize,
+    /// The host has less memory available (or fewer free hugepages) than the guest needs,
+    /// in MiB
+    InsufficientMemory { requested: usize, available: usize },
+    /// Guest memory must be a whole number of hugepages
+    InvalidMemSize {
+        mem_size_mib: usize,
+        page_size_mib: usize,
+    },
+    /// `/dev/kvm` cannot be opened, or lacks a capability firecracker needs
+    KvmUnavailable(io::Error),
+    /// The event manager failed to set up or poll its epoll instance
+    EventManager(EventManagerError),
+    /// The VMM failed to build the microVM
+    BuildMicrovm(StartMicrovmError),
+    /// The VMM failed an operation on a running microVM
+    Vmm(VmmError),
+    /// The VM was started without a `ready_marker`
+    NoReadyMarker,
+    /// The guest did not write its `ready_marker` in time
+    ReadyTimeout,
+    /// No console line matched within the timeout of `wait_for_serial`
+    SerialTimeout,
+    /// The console of a restored VM is not watched, so `wait_for_serial` cannot work
+    SerialNotWatched,
+    /// The VM is already paused
+    AlreadyPaused,
+    /// The VM is not paused
+    NotPaused,
+    /// The VM was stopped through a `ShutdownSignal` before the guest exited
+    Terminated,
+    /// The guest did not exit before the VM's `timeout`
+    Timeout,
+    /// Creating a snapshot failed
+    CreateSnapshot(CreateSnapshotError),
+    /// Restoring a snapshot failed
+    RestoreSnapshot(RestoreFromSnapshotError),
+    /// A diff snapshot or dirty pages were requested for a VM booted without
+    /// `track_dirty_pages`
+    DirtyPageTrackingDisabled,
+    /// Guest memory could not be read
+    GuestMemory(GuestMemoryError),
+    /// Firecracker's metrics could not be set up, e.g. because they already were
+    Metrics(MetricsError),
+    /// The logger could not be installed, because another one already was
+    Logger(log::SetLoggerError),
+    /// A background thread could not be spawned
+    Thread(io::Error),
+    /// The guest kernel panicked, printing `message` and then `backtrace_lines`
+    GuestPanicked {
+        message: String,
+        backtrace_lines: Vec<String>,
+    },
+    /// Entering the VM's network namespace failed
+    NetNs(io::Error),
+    /// The restored VM's vsock directory `path` could not be mounted
+    VsockDir { path: PathBuf, source: io::Error },
+    /// The event loop thread panicked
+    EventLoopPanicked,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::MissingKernel => write!(f, "no kernel was configured"),
+            SpawnError::Kernel {
+                path: Some(path),
+                source,
+            } => write!(f, "invalid kernel {}: {source}", path.display()),
+            SpawnError::Kernel { path: None, source } => write!(f, "invalid kernel: {source}"),
+            SpawnError::Initrd(e) => write!(f, "invalid initrd: {e}"),
+            SpawnError::Cmdline(e) => write!(f, "invalid kernel cmdline: {e}"),
+            SpawnError::CmdlineTooLong { len, capacity } => write!(
+                f,
+                "kernel cmdline is {len} bytes, it must be shorter than the {capacity} byte capacity"
+            ),
+            SpawnError::InvalidCmdlineArg { arg } => {
+                write!(f, "kernel cmdline arg {arg:?} contains a newline or NUL")
+            }
+            SpawnError::RootFlagConflict { flag } => write!(
+                f,
+                "kernel cmdline has {flag}, which conflicts with how the root disk is attached"
+            ),
+            SpawnError::Disk { id, source } => write!(f, "failed to attach disk {id}: {source}"),
+            SpawnError::Net { iface_id, source } => {
+                write!(f, "failed to attach network interface {iface_id}: {source}")
+            }
+            SpawnError::Vsock(e) => write!(f, "failed to attach vsock device: {e}"),
+            SpawnError::Entropy(e) => write!(f, "failed to attach entropy device: {e}"),
+            SpawnError::BalloonConfig(e) => write!(f, "failed to attach balloon device: {e}"),
+            SpawnError::Balloon(e) => write!(f, "balloon operation failed: {e}"),
+            SpawnError::BalloonTimeout {
+                target_mib,
+                actual_mib,
+            } => write!(
+                f,
+                "balloon reached {actual_mib} of {target_mib} MiB before the timeout"
+            ),
+            SpawnError::Mmds(e) => write!(f, "failed to configure MMDS: {e}"),
+            SpawnError::MmdsData(e) => write!(f, "invalid MMDS data: {e}"),
+            SpawnError::MmdsNotObject { key } => {
+                write!(f, "MMDS data at {key} must be a JSON object")
+            }
+            SpawnError::MmdsNotAllowed => {
+                write!(f, "MMDS requires a network interface with allow_mmds set")
+            }
+            SpawnError::MmdsUserData { path, source } => {
+                write!(f, "cannot read MMDS user-data {}: {source}", path.display())
+            }
+            SpawnError::UnknownCpuTemplate { name } => {
+                write!(f, "unknown CPU template {name} for this architecture")
+            }
+            SpawnError::CpuTemplateFile { path, source } => {
+                write!(f, "failed to read CPU template {}: {source}", path.display())
+            }
+            SpawnError::CpuTemplateParse { path, source } => {
+                write!(f, "invalid CPU template {}: {source}", path.display())
+            }
+            SpawnError::SeccompFile { path, source } => {
+                write!(f, "failed to read seccomp filter {}: {source}", path.display())
+            }
+            SpawnError::ConfigFile { path, source } => {
+                write!(f, "failed to read config file {}: {source}", path.display())
+            }
+            SpawnError::ConfigParse { path, source } => {
+                write!(f, "invalid config file {}: {source}", path.display())
+            }
+            SpawnError::ConfigUnsupported { field } => {
+                write!(f, "unsupported config file option: {field}")
+            }
+            SpawnError::Seccomp(e) => write!(f, "invalid seccomp filters: {e}"),
+            SpawnError::SeccompApply(e) => write!(f, "failed to install seccomp filter: {e}"),
+            SpawnError::NoBootDevice => write!(
+                f,
+                "neither a rootfs nor an initrd was configured, and the cmdline sets no root= or init="
+            ),
+            SpawnError::MultipleRootDisks => write!(f, "more than one disk is the root device"),
+            SpawnError::DiskBackend { id } => {
+                write!(f, "disk {id} must have exactly one of a path or a socket")
+            }
+            SpawnError::DiskNotFound { id, path } => {
+                write!(f, "backing file for disk {id} not found: {}", path.display())
+            }
+            SpawnError::UnknownDrive { id } => write!(f, "the VM has no disk {id}"),
+            SpawnError::InvalidMac { mac } => write!(f, "invalid MAC address {mac:?}"),
+            SpawnError::EmptyTapName => write!(f, "network interface has an empty TAP name"),
+            SpawnError::TapNotFound { name } => {
+                write!(f, "no TAP interface named {name}, create it with `ip tuntap add {name} mode tap`")
+            }
+            SpawnError::TapBusy { name } => write!(f, "TAP interface {name} is already in use"),
+            SpawnError::ReservedCid { cid } => write!(f, "guest CID {cid} is reserved"),
+            SpawnError::CidInUse { cid } => write!(f, "guest CID {cid} is already in use"),
+            SpawnError::InvalidVcpuCount { requested, max } => {
+                write!(f, "invalid vcpu count {requested}, must be between 1 and {max}")
+            }
+            SpawnError::SmtNotSupported => write!(f, "SMT is not supported on this architecture"),
+            SpawnError::OddVcpuCountWithSmt { requested } => write!(
+                f,
+                "invalid vcpu count {requested}, SMT requires 1 or an even number of vcpus"
+            ),
+            SpawnError::UnsupportedHugePages { page_size_mib } => {
+                write!(f, "{page_size_mib} MiB hugepages are not supported")
+            }
+            SpawnError::ZeroMemSize => write!(f, "memory size must be at least 1 MiB"),
+            SpawnError::InsufficientMemory {
+                requested,
+                available,
+            } => write!(
+                f,
+                "guest needs {requested} MiB of memory but the host has {available} MiB available"
+            ),
+            SpawnError::InvalidMemSize {
+                mem_size_mib,
+                page_size_mib,
+            } => write!(
+                f,
+                "memory size {mem_size_mib} MiB is not a multiple of the {page_size_mib} MiB page size"
+            ),
+            SpawnError::VcpuAffinityLength { len, vcpu_count } => write!(
+                f,
+                "vcpu affinity lists {len} host CPUs for {vcpu_count} vcpus"
+            ),
+            SpawnError::CpuOffline { cpu } => write!(f, "host CPU {cpu} is not online"),
+            SpawnError::VcpuAffinity(e) => write!(f, "failed to pin vcpu threads: {e}"),
+            SpawnError::VcpuThreads(e) => write!(f, "failed to inspect vcpu threads: {e}"),
+            SpawnError::KvmUnavailable(e) if e.kind() == io::ErrorKind::PermissionDenied => write!(
+                f,
+                "KVM is unavailable: {e}, add your user to the kvm group to access /dev/kvm"
+            ),
+            SpawnError::KvmUnavailable(e) => write!(f, "KVM is unavailable: {e}"),
+            SpawnError::EventManager(e) => write!(f, "event manager failure: {e}"),
+            SpawnError::BuildMicrovm(e) => write!(f, "failed to build microVM: {e}"),
+            SpawnError::Vmm(e) => write!(f, "VMM failure: {e}"),
+            SpawnError::NoReadyMarker => write!(f, "the VM has no ready marker to wait for"),
+            SpawnError::ReadyTimeout => write!(f, "timed out waiting for the guest to be ready"),
+            SpawnError::SerialTimeout => {
+                write!(f, "timed out waiting for a matching console line")
+            }
+            SpawnError::SerialNotWatched => {
+                write!(f, "the console of a restored VM is not watched")
+            }
+            SpawnError::AlreadyPaused => write!(f, "the VM is already paused"),
+            SpawnError::NotPaused => write!(f, "the VM is not paused"),
+            SpawnError::Terminated => write!(f, "the VM was terminated"),
+            SpawnError::Timeout => write!(f, "the VM timed out"),
+            SpawnError::CreateSnapshot(e) => write!(f, "failed to create snapshot: {e}"),
+            SpawnError::RestoreSnapshot(e) => write!(f, "failed to restore snapshot: {e}"),
+            SpawnError::DirtyPageTrackingDisabled => {
+                write!(f, "dirty page tracking requires track_dirty_pages to be enabled at boot")
+            }
+            SpawnError::GuestMemory(e) => write!(f, "failed to access guest memory: {e}"),
+            SpawnError::Metrics(e) => write!(f, "failed to set up metrics: {e}"),
+            SpawnError::Logger(e) => write!(f, "failed to install the logger: {e}"),
+            SpawnError::Thread(e) => write!(f, "failed to spawn thread: {e}"),
+            SpawnError::GuestPanicked { message, .. } => {
+                write!(f, "guest kernel panicked: {message}")
+            }
+            SpawnError::NetNs(e) => write!(f, "failed to enter the network namespace: {e}"),
+            SpawnError::VsockDir { path, source } => write!(
+                f,
+                "failed to mount the vsock directory {}: {source}",
+                path.display()
+            ),
+            SpawnError::EventLoopPanicked => write!(f, "event loop thread panicked"),
+        }
+    }
+}
+
+impl Error for SpawnError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SpawnError::Kernel { source, .. } => Some(source),
+            SpawnError::Initrd(e)
+            | SpawnError::VcpuAffinity(e)
+            | SpawnError::VcpuThreads(e)
+            | SpawnError::NetNs(e)
+            | SpawnError::KvmUnavailable(e)
+            | SpawnError::Thread(e) => Some(e),
+            SpawnError::Cmdline(e) => Some(e),
+            SpawnError::Disk { source, .. } => Some(source),
+            SpawnError::Net { source, .. } => Some(source),
+            SpawnError::Vsock(e) => Some(e),
+            SpawnError::Entropy(e) => Some(e),
+            SpawnError::BalloonConfig(e) => Some(e),
+            SpawnError::Balloon(e) => Some(e),
+            SpawnError::Mmds(e) => Some(e),
+            SpawnError::MmdsData(e) => Some(e),
+            SpawnError::MmdsUserData { source, .. } => Some(source),
+            SpawnError::CpuTemplateFile { source, .. } => Some(source),
+            SpawnError::CpuTemplateParse { source, .. } => Some(source),
+            SpawnError::ConfigFile { source, .. } => Some(source),
+            SpawnError::VsockDir { source, .. } => Some(source),
+            SpawnError::ConfigParse { source, .. } => Some(source),
+            SpawnError::SeccompFile { source, .. } => Some(source),
+            SpawnError::Seccomp(e) => Some(e),
+            SpawnError::SeccompApply(e) => Some(e),
+            SpawnError::EventManager(e) => Some(e),
+            SpawnError::BuildMicrovm(e) => Some(e),
+            SpawnError::Vmm(e) => Some(e),
+            SpawnError::CreateSnapshot(e) => Some(e),
+            SpawnError::RestoreSnapshot(e) => Some(e),
+            SpawnError::GuestMemory(e) => Some(e),
+            SpawnError::Metrics(e) => Some(e),
+            SpawnError::Logger(e) => Some(e),
+            SpawnError::MissingKernel
+            | SpawnError::CmdlineTooLong { .. }
+            | SpawnError::InvalidCmdlineArg { .. }
+            | SpawnError::RootFlagConflict { .. }
+            | SpawnError::UnknownCpuTemplate { .. }
+            | SpawnError::ConfigUnsupported { .. }
+            | SpawnError::NoBootDevice
+            | SpawnError::MultipleRootDisks
+            | SpawnError::DiskBackend { .. }
+            | SpawnError::DiskNotFound { .. }
+            | SpawnError::UnknownDrive { .. }
+            | SpawnError::MmdsNotObject { .. }
+            | SpawnError::MmdsNotAllowed
+            | SpawnError::InvalidMac { .. }
+            | SpawnError::EmptyTapName
+            | SpawnError::TapNotFound { .. }
+            | SpawnError::TapBusy { .. }
+            | SpawnError::ReservedCid { .. }
+            | SpawnError::CidInUse { .. }
+            | SpawnError::InvalidVcpuCount { .. }
+            | SpawnError::SmtNotSupported
+            | SpawnError::OddVcpuCountWithSmt { .. }
+            | SpawnError::VcpuAffinityLength { .. }
+            | SpawnError::CpuOffline { .. }
+            | SpawnError::UnsupportedHugePages { .. }
+            | SpawnError::InvalidMemSize { .. }
+            | SpawnError::ZeroMemSize
+            | SpawnError::InsufficientMemory { .. }
+            | SpawnError::NoReadyMarker
+            | SpawnError::ReadyTimeout
+            | SpawnError::SerialTimeout
+            | SpawnError::SerialNotWatched
+            | SpawnError::BalloonTimeout { .. }
+            | SpawnError::AlreadyPaused
+            | SpawnError::NotPaused
+            | SpawnError::Terminated
+            | SpawnError::Timeout
+            | SpawnError::DirtyPageTrackingDisabled
+            | SpawnError::GuestPanicked { .. }
+            | SpawnError::EventLoopPanicked => None,
+        }
+    }
+}
+
+impl From<CmdlineError> for SpawnError {
+    fn from(e: CmdlineError) -> Self {
+        SpawnError::Cmdline(e)
+    }
+}
+
+impl From<VsockConfigError> for SpawnError {
+    fn from(e: VsockConfigError) -> Self {
+        SpawnError::Vsock(e)
+    }
+}
+
+impl From<EntropyDeviceError> for SpawnError {
+    fn from(e: EntropyDeviceError) -> Self {
+        SpawnError::Entropy(e)
+    }
+}
+
+impl From<BalloonConfigError> for SpawnError {
+    fn from(e: BalloonConfigError) -> Self {
+        SpawnError::BalloonConfig(e)
+    }
+}
+
+impl From<MmdsConfigError> for SpawnError {
+    fn from(e: MmdsConfigError) -> Self {
+        SpawnError::Mmds(e)
+    }
+}
+
+impl From<MmdsDatastoreError> for SpawnError {
+    fn from(e: MmdsDatastoreError) -> Self {
+        SpawnError::MmdsData(e)
+    }
+}
+
+impl From<EventManagerError> for SpawnError {
+    fn from(e: EventManagerError) -> Self {
+        SpawnError::EventManager(e)
+    }
+}
+
+impl From<StartMicrovmError> for SpawnError {
+    fn from(e: StartMicrovmError) -> Self {
+        SpawnError::BuildMicrovm(e)
+    }
+}
+
+impl From<CreateSnapshotError> for SpawnError {
+    fn from(e: CreateSnapshotError) -> Self {
+        SpawnError::CreateSnapshot(e)
+    }
+}
+
+impl From<RestoreFromSnapshotError> for SpawnError {
+    fn from(e: RestoreFromSnapshotError) -> Self {
+        SpawnError::RestoreSnapshot(e)
+    }
+}
+
+impl From<VmmError> for SpawnError {
+    fn from(e: VmmError) -> Self {
+        SpawnError::Vmm(e)
+    }
+}
@@ -0,0 +1,827 @@
+use std::fs::{self, File};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use regex::Regex;
+use seccompiler::BpfThreadMap;
+use vmm::builder::build_microvm_for_boot;
+use vmm::persist::{create_snapshot, restore_from_snapshot, VmInfo as PersistVmInfo};
+use vmm::resources::VmResources;
+use vmm::vmm_config::instance_info::{InstanceInfo, VmState};
+use vmm::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
+use vmm::vstate::memory::{Bitmap, Bytes, GuestAddress, GuestMemory, GuestMemoryRegion};
+use vmm::{EventManager, FcExitCode, Vmm};
+
+use crate::affinity;
+use crate::boottime::BootTime;
+use crate::cid::CidLease;
+use crate::serial::{ConsoleTail, GuestPanic, PanicReport, PanicWatcher, Ready, SerialWaiters};
+use crate::{BalloonStats, ExitReason, OwnedNet, SerialOut, SpawnError};
+
+/// How long the event loop waits for events before checking whether the VM has exited.
+const EVENT_LOOP_TIMEOUT_MS: i32 = 100;
+
+/// How often `inflate_balloon` checks how far the guest has inflated the balloon.
+const BALLOON_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often `shutdown_graceful` checks whether the guest has exited.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A booted microVM whose event loop runs on a background thread.
+///
+/// Dropping the handle stops the VM, joins the event loop thread and then flushes the
+/// backing files of its writable disks to stable storage.
+pub struct VmHandle {
+    id: String,
+    vmm: Arc<Mutex<Vmm>>,
+    thread: Option<JoinHandle<Result<FcExitCode, SpawnError>>>,
+    vm_info: PersistVmInfo,
+    track_dirty_pages: bool,
+    paused: AtomicBool,
+    ready: Option<Arc<Ready>>,
+    /// Flagged when the guest kernel panics; not watched for restored VMs
+    panicked: Option<Arc<GuestPanic>>,
+    started_at: Instant,
+    terminated: Arc<AtomicBool>,
+    summary: Summary,
+    /// Host thread ids of the vcpus, with their index
+    vcpu_threads: Vec<(libc::pid_t, usize)>,
+    /// Set by the boot timer device, if the VM has one
+    boot_time: Option<BootTime>,
+    /// The last console lines, if the VM keeps them
+    pub(crate) console_tail: Option<Arc<ConsoleTail>>,
+    /// Patterns waited for on the console, not watched for restored VMs
+    pub(crate) serial: Option<Arc<SerialWaiters>>,
+    /// Keeps the vsock CID reserved while the VM runs
+    pub(crate) cid: Option<CidLease>,
+    /// The vsock socket firecracker created, removed on drop
+    pub(crate) vsock_path: Option<PathBuf>,
+    /// Backing files of the writable disks, synced on drop
+    pub(crate) writable_disks: Vec<PathBuf>,
+    /// Network interfaces shared with the `Vm`, dropped after the VM has stopped
+    pub(crate) owned_nets: Vec<OwnedNet>,
+    /// Resolves once the event loop thread is done, see [`VmHandle::wait_async`]
+    #[cfg(feature = "tokio")]
+    exited: Option<tokio::sync::oneshot::Receiver<()>>,
+}
+
+/// Stops a running VM from any thread, see [`VmHandle::shutdown_signal`].
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    vmm: Arc<Mutex<Vmm>>,
+    terminated: Arc<AtomicBool>,
+}
+
+impl ShutdownSignal {
+    /// Kills the vcpus and makes the event loop exit; [`VmHandle::wait`] then fails with
+    /// `SpawnError::Terminated`. Does nothing if the guest has already exited.
+    ///
+    /// Only takes the VMM lock the event loop takes between iterations, so it cannot
+    /// deadlock with it.
+    pub fn shutdown(&self) {
+        let mut vmm = self.vmm.lock().unwrap();
+        if vmm.shutdown_exit_code().is_none() {
+            self.terminated.store(true, Ordering::SeqCst);
+            vmm.stop(FcExitCode::Ok);
+        }
+    }
+}
+
+/// A running VM as handed back by the event loop thread
+struct Booted {
+    vmm: Arc<Mutex<Vmm>>,
+    vm_info: PersistVmInfo,
+    track_dirty_pages: bool,
+    summary: Summary,
+    vcpu_threads: Vec<(libc::pid_t, usize)>,
+    boot_time: Option<BootTime>,
+}
+
+/// CPU time used by one vcpu, see [`VmHandle::cpu_stats`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VcpuCpuTime {
+    pub vcpu: usize,
+    /// Time spent running guest code or in the vcpu thread's user space
+    pub user: Duration,
+    /// Time spent in the host kernel on the vcpu thread's behalf, e.g. handling exits
+    pub system: Duration,
+}
+
+/// What a running VM was configured with, see [`VmHandle::info`]
+#[derive(Clone, Debug)]
+pub struct VmInfo {
+    pub id: String,
+    pub state: VmState,
+    /// Set once the guest has exited
+    pub exit_code: Option<FcExitCode>,
+    pub vcpu_count: u8,
+    pub mem_size_mib: usize,
+    /// Ids of the block devices, e.g. `block0` for the rootfs
+    pub drive_ids: Vec<String>,
+    /// Ids of the network interfaces, e.g. `net0`
+    pub net_ids: Vec<String>,
+    pub vsock: bool,
+}
+
+/// The static part of [`VmInfo`], read from the resources the VM was built from
+struct Summary {
+    vcpu_count: u8,
+    mem_size_mib: usize,
+    drive_ids: Vec<String>,
+    net_ids: Vec<String>,
+    vsock: bool,
+}
+
+impl Summary {
+    fn from_resources(vm_resources: &VmResources) -> Self {
+        Summary {
+            vcpu_count: vm_resources.vm_config.vcpu_count,
+            mem_size_mib: vm_resources.vm_config.mem_size_mib,
+            drive_ids: vm_resources
+                .block
+                .configs()
+                .into_iter()
+                .map(|cfg| cfg.drive_id)
+                .collect(),
+            net_ids: vm_resources
+                .net_builder
+                .configs()
+                .into_iter()
+                .map(|cfg| cfg.iface_id)
+                .collect(),
+            vsock: vm_resources.vsock.get().is_some(),
+        }
+    }
+}
+
+impl VmHandle {
+    /// Boots a VM from `vm_resources`. `ready` is flagged by the serial output, if watched.
+    /// The serial output is also watched for kernel panics.
+    ///
+    /// With a `vcpu_affinity`, the vcpu threads are pinned before the VM is resumed.
+    pub(crate) fn boot(
+        instance_info: InstanceInfo,
+        vm_resources: VmResources,
+        seccomp_filters: BpfThreadMap,
+        timeout: Option<Duration>,
+        output: Box<dyn SerialOut>,
+        ready: Option<Arc<Ready>>,
+        vcpu_affinity: Option<Vec<usize>>,
+    ) -> Result<VmHandle, SpawnError> {
+        let panicked = Arc::new(GuestPanic::default());
+        let output = Box::new(PanicWatcher::new(output, panicked.clone()));
+        let mut handle = Self::spawn(
+            instance_info.id.clone(),
+            seccomp_filters,
+            timeout,
+            Some(panicked),
+            move |event_manager, seccomp_filters| {
+                let build = |event_manager: &mut EventManager| {
+                    build_microvm_for_boot(
+                        &instance_info,
+                        &vm_resources,
+                        event_manager,
+                        seccomp_filters,
+                        output,
+                    )
+                };
+                let (vmm, vcpu_threads) = affinity::track_vcpus(|| Ok(build(event_manager)?))?;
+                if let Some(affinity) = vcpu_affinity {
+                    affinity::pin_vcpus(&vcpu_threads, &affinity)?;
+                }
+                // Known to the logger before the guest can write to the boot timer
+                let boot_time = vm_resources
+                    .boot_timer
+                    .then(|| BootTime::register(&vcpu_threads));
+                vmm.lock().unwrap().resume_vm()?;
+                Ok(Booted {
+                    vmm,
+                    vm_info: PersistVmInfo::from(&vm_resources),
+                    track_dirty_pages: vm_resources.vm_config.track_dirty_pages,
+                    summary: Summary::from_resources(&vm_resources),
+                    vcpu_threads,
+                    boot_time,
+                })
+            },
+        )?;
+        handle.ready = ready;
+        Ok(handle)
+    }
+
+    /// Restores a VM from a snapshot, swapping in `disk_paths` before resuming it.
+    pub(crate) fn restore(
+        instance_info: InstanceInfo,
+        params: LoadSnapshotParams,
+        disk_paths: Vec<(String, PathBuf)>,
+        seccomp_filters: BpfThreadMap,
+        timeout: Option<Duration>,
+    ) -> Result<VmHandle, SpawnError> {
+        Self::spawn(
+            instance_info.id.clone(),
+            seccomp_filters,
+            timeout,
+            None,
+            move |event_manager, seccomp_filters| {
+                let mut vm_resources = VmResources::default();
+                let (vmm, vcpu_threads) = affinity::track_vcpus(|| {
+                    Ok(restore_from_snapshot(
+                        &instance_info,
+                        event_manager,
+                        seccomp_filters,
+                        &params,
+                        &mut vm_resources,
+                    )?)
+                })?;
+                {
+                    let mut locked = vmm.lock().unwrap();
+                    for (drive_id, path) in disk_paths {
+                        locked.update_block_device_path(&drive_id, path.display().to_string())?;
+                    }
+                    locked.resume_vm()?;
+                }
+                Ok(Booted {
+                    vmm,
+                    vm_info: PersistVmInfo::from(&vm_resources),
+                    track_dirty_pages: params.enable_diff_snapshots,
+                    // Only the machine config is restored into `vm_resources`, not the devices
+                    summary: Summary::from_resources(&vm_resources),
+                    vcpu_threads,
+                    // The guest booted before the snapshot was taken
+                    boot_time: None,
+                })
+            },
+        )
+    }
+
+    /// Runs `build` on a new event loop thread, which then keeps driving the VM it returns.
+    ///
+    /// `build` installs the `vcpu` filter on the vcpu threads it starts; the `vmm` filter is
+    /// installed on the event loop thread once the VM is running.
+    ///
+    /// The VM is stopped if it is still running after `timeout`, or once `panicked` is
+    /// flagged, as a guest booted with `panic=0` hangs after a panic.
+    fn spawn<F>(
+        id: String,
+        seccomp_filters: BpfThreadMap,
+        timeout: Option<Duration>,
+        panicked: Option<Arc<GuestPanic>>,
+        build: F,
+    ) -> Result<VmHandle, SpawnError>
+    where
+        F: FnOnce(&mut EventManager, &BpfThreadMap) -> Result<Booted, SpawnError> + Send + 'static,
+    {
+        let started_at = Instant::now();
+        let (tx, rx) = mpsc::channel();
+        let thread_vm_id = id.clone();
+        let thread_panicked = panicked.clone();
+        #[cfg(feature = "tokio")]
+        let (exit_tx, exit_rx) = tokio::sync::oneshot::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("fc_vmm".to_string())
+            .spawn(move || {
+                // Dropped however the thread ends, which wakes `wait_async`
+                #[cfg(feature = "tokio")]
+                let _exit_tx = exit_tx;
+                // The event manager is not `Send`, so it must live on the thread that runs it.
+                let mut event_manager = EventManager::new()?;
+                let booted = build(&mut event_manager, &seccomp_filters)?;
+                if let Some(filter) = seccomp_filters.get("vmm") {
+                    seccompiler::apply_filter(filter).map_err(SpawnError::SeccompApply)?;
+                }
+                let vm = booted.vmm.clone();
+                let _ = tx.send(booted);
+
+                loop {
+                    event_manager.run_with_timeout(EVENT_LOOP_TIMEOUT_MS)?;
+                    let mut locked = vm.lock().unwrap();
+                    if let Some(exit_code) = locked.shutdown_exit_code() {
+                        info!("{thread_vm_id}: exited with {exit_code:?}");
+                        return Ok(exit_code);
+                    }
+                    if thread_panicked.as_ref().is_some_and(|p| p.seen()) {
+                        warn!("{thread_vm_id}: guest kernel panicked, stopping it");
+                        locked.stop(FcExitCode::GenericError);
+                        continue;
+                    }
+                    if timeout.is_some_and(|timeout| started_at.elapsed() >= timeout) {
+                        warn!("{thread_vm_id}: timed out, stopping it");
+                        locked.stop(FcExitCode::Ok);
+                        return Err(SpawnError::Timeout);
+                    }
+                }
+            })
+            .map_err(SpawnError::Thread)?;
+
+        match rx.recv() {
+            Ok(booted) => Ok(VmHandle {
+                id,
+                vmm: booted.vmm,
+                thread: Some(thread),
+                vm_info: booted.vm_info,
+                track_dirty_pages: booted.track_dirty_pages,
+                paused: AtomicBool::new(false),
+                ready: None,
+                panicked,
+                started_at,
+                terminated: Arc::new(AtomicBool::new(false)),
+                summary: booted.summary,
+                vcpu_threads: booted.vcpu_threads,
+                boot_time: booted.boot_time,
+                console_tail: None,
+                serial: None,
+                cid: None,
+                vsock_path: None,
+                writable_disks: Vec::new(),
+                owned_nets: Vec::new(),
+                #[cfg(feature = "tokio")]
+                exited: Some(exit_rx),
+            }),
+            // The sender is only dropped without sending when booting failed.
+            Err(_) => match thread.join() {
+                Ok(Err(e)) => Err(e),
+                _ => Err(SpawnError::EventLoopPanicked),
+            },
+        }
+    }
+
+    /// The instance id the VM was started with
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The CID the guest's vsock device was given, if it has one. Not known for restored VMs.
+    pub fn guest_cid(&self) -> Option<u32> {
+        self.cid.as_ref().map(CidLease::cid)
+    }
+
+    /// The VM's current state and configuration. Device ids are empty for restored VMs.
+    pub fn info(&self) -> VmInfo {
+        let vmm = self.vmm.lock().unwrap();
+        VmInfo {
+            id: self.id.clone(),
+            state: vmm.instance_info().state,
+            exit_code: vmm.shutdown_exit_code(),
+            vcpu_count: self.summary.vcpu_count,
+            mem_size_mib: self.summary.mem_size_mib,
+            drive_ids: self.summary.drive_ids.clone(),
+            net_ids: self.summary.net_ids.clone(),
+            vsock: self.summary.vsock,
+        }
+    }
+
+    /// CPU time each vcpu has used, as accounted by the host for its thread. The times are
+    /// cumulative since the VM started, or was restored; diff two readings for the usage
+    /// over an interval. Vcpus whose thread has exited, e.g. after the guest shut down, are
+    /// left out.
+    pub fn cpu_stats(&self) -> Result<Vec<VcpuCpuTime>, SpawnError> {
+        affinity::cpu_times(&self.vcpu_threads).map_err(SpawnError::VcpuThreads)
+    }
+
+    /// Host thread ids of the vcpus, with their index
+    #[cfg(test)]
+    pub(crate) fn vcpu_threads(&self) -> &[(libc::pid_t, usize)] {
+        &self.vcpu_threads
+    }
+
+    /// The last lines the guest wrote to its console, oldest first, as many as the VM's
+    /// `console_tail` keeps. Line endings are stripped, and a line still being written is
+    /// left out. Empty for VMs that keep no lines, and for restored VMs.
+    pub fn console_tail(&self) -> Vec<String> {
+        self.console_tail
+            .as_ref()
+            .map_or_else(Vec::new, |tail| tail.lines())
+    }
+
+    /// Blocks until the guest writes a console line matching `pattern`, for up to `timeout`,
+    /// and returns that line with its line ending stripped. Only output written after the
+    /// call is matched, including the line still being written, so prompts such as
+    /// `login: ` are seen. For a marker the guest may print before the caller gets here, use
+    /// the VM's `ready_marker` and [`VmHandle::wait_ready`] instead.
+    ///
+    /// Fails with `SpawnError::SerialTimeout` if no line matched in time, and with
+    /// `SpawnError::SerialNotWatched` for restored VMs, whose console is not watched.
+    pub fn wait_for_serial(
+        &self,
+        pattern: &Regex,
+        timeout: Duration,
+    ) -> Result<String, SpawnError> {
+        let serial = self.serial.as_ref().ok_or(SpawnError::SerialNotWatched)?;
+        serial
+            .wait(pattern, timeout)
+            .ok_or(SpawnError::SerialTimeout)
+    }
+
+    /// Blocks until the guest writes the VM's `ready_marker` to its serial console, for up to
+    /// `timeout`. Returns immediately once the marker has been seen.
+    pub fn wait_ready(&self, timeout: Duration) -> Result<(), SpawnError> {
+        let ready = self.ready.as_ref().ok_or(SpawnError::NoReadyMarker)?;
+        match ready.wait(timeout) {
+            Some(_) => Ok(()),
+            None => Err(SpawnError::ReadyTimeout),
+        }
+    }
+
+    /// Time from starting the VM until the guest wrote its `ready_marker`, or `None` if it
+    /// has not yet. This is measured from the host; for the guest's own boot time see
+    /// [`VmHandle::boot_time`].
+    pub fn ready_after(&self) -> Option<Duration> {
+        let seen_at = self.ready.as_ref()?.wait(Duration::ZERO)?;
+        Some(seen_at - self.started_at)
+    }
+
+    /// The guest's boot time as measured by the boot timer device, see
+    /// [`Vm::boot_timer`](crate::Vm::boot_timer), once the guest has written to it. Only kept
+    /// with the logger of [`init_logger`](crate::init_logger) installed; `None` until then,
+    /// and for restored VMs.
+    pub fn boot_time(&self) -> Option<Duration> {
+        self.boot_time.as_ref()?.get()
+    }
+
+    /// Pauses all vcpus. Fails with `SpawnError::AlreadyPaused` if the VM is paused.
+    pub fn pause(&self) -> Result<(), SpawnError> {
+        let mut vmm = self.vmm.lock().unwrap();
+        if self.paused.load(Ordering::SeqCst) {
+            return Err(SpawnError::AlreadyPaused);
+        }
+        vmm.pause_vm()?;
+        self.paused.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Resumes a paused VM. Fails with `SpawnError::NotPaused` if the VM is running.
+    pub fn resume(&self) -> Result<(), SpawnError> {
+        let mut vmm = self.vmm.lock().unwrap();
+        if !self.paused.load(Ordering::SeqCst) {
+            return Err(SpawnError::NotPaused);
+        }
+        vmm.resume_vm()?;
+        self.paused.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Presses Ctrl+Alt+Del on the guest's i8042 keyboard, asking the guest to shut down.
+    /// Once it reboots the event loop exits and `wait` returns.
+    #[cfg(target_arch = "x86_64")]
+    pub fn send_ctrl_alt_del(&self) -> Result<(), SpawnError> {
+        self.vmm.lock().unwrap().send_ctrl_alt_del()?;
+        Ok(())
+    }
+
+    /// Presses Ctrl+Alt+Del and gives the guest up to `timeout` to shut down, then stops it
+    /// like `shutdown` if it has not. Returns what `wait` returns, so a guest that ignored
+    /// the request fails with `SpawnError::Terminated`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn shutdown_graceful(self, timeout: Duration) -> Result<FcExitCode, SpawnError> {
+        if self.try_wait()?.is_none() {
+            self.send_ctrl_alt_del()?;
+            let deadline = Instant::now() + timeout;
+            while self.try_wait()?.is_none() {
+                if Instant::now() >= deadline {
+                    warn!("{}: guest did not shut down in {timeout:?}", self.id);
+                    self.shutdown();
+                    break;
+                }
+                thread::sleep(EXIT_POLL_INTERVAL);
+            }
+        }
+        self.wait()
+    }
+
+    /// Resizes the balloon device, taking `amount_mib` of memory away from the guest.
+    pub fn balloon_set_target(&self, amount_mib: u32) -> Result<(), SpawnError> {
+        self.vmm
+            .lock()
+            .unwrap()
+            .update_balloon_config(amount_mib)
+            .map_err(SpawnError::Balloon)
+    }
+
+    /// The balloon's target and actual size, and the guest's latest memory statistics.
+    /// Requires a `stats_polling_interval_s` above 0.
+    pub fn balloon_stats(&self) -> Result<BalloonStats, SpawnError> {
+        self.vmm
+            .lock()
+            .unwrap()
+            .latest_balloon_stats()
+            .map_err(SpawnError::Balloon)
+    }
+
+    /// Resizes the balloon to `amount_mib` and blocks until the guest has handed over that
+    /// much memory, for up to `timeout`.
+    ///
+    /// Inflating the balloon before [`VmHandle::snapshot`] leaves the pages it holds out of
+    /// the memory file's contents, shrinking sparse copies and diff snapshots; see
+    /// [`VmHandle::snapshot_compacted`], and deflate it with `balloon_set_target(0)` once the
+    /// snapshot is restored. Requires a
+    /// `stats_polling_interval_s` above 0.
+    pub fn inflate_balloon(&self, amount_mib: u32, timeout: Duration) -> Result<(), SpawnError> {
+        self.balloon_set_target(amount_mib)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let stats = self.balloon_stats()?;
+            if stats.actual_mib >= amount_mib {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(SpawnError::BalloonTimeout {
+                    target_mib: amount_mib,
+                    actual_mib: stats.actual_mib,
+                });
+            }
+            thread::sleep(BALLOON_POLL_INTERVAL);
+        }
+    }
+
+    /// Swaps the backing file of block device `drive_id`, e.g. `block1`, while the VM runs.
+    /// The guest is notified of the new size, but not of the new contents: unmount the disk
+    /// in the guest first, as its page cache and filesystem state still describe the old
+    /// file.
+    ///
+    /// Firecracker cannot hot-plug devices, so this only replaces disks attached at boot; to
+    /// attach one later, boot with a small placeholder file in its place. Disks served by a
+    /// vhost-user backend cannot be updated.
+    pub fn update_drive(&self, drive_id: &str, path: impl AsRef<Path>) -> Result<(), SpawnError> {
+        let path = path.as_ref();
+        // The drives of restored VMs are unknown; firecracker rejects unknown ids itself
+        if !self.summary.drive_ids.is_empty()
+            && !self.summary.drive_ids.iter().any(|id| id == drive_id)
+        {
+            return Err(SpawnError::UnknownDrive {
+                id: drive_id.to_string(),
+            });
+        }
+        if !path.exists() {
+            return Err(SpawnError::DiskNotFound {
+                id: drive_id.to_string(),
+                path: path.to_path_buf(),
+            });
+        }
+        self.vmm
+            .lock()
+            .unwrap()
+            .update_block_device_path(drive_id, path.display().to_string())?;
+        Ok(())
+    }
+
+    /// Pauses the VM (if it is not already paused) and writes its memory to `mem_path` and
+    /// its device state to `state_path`. The VM is resumed afterwards if `resume` is set, and
+    /// left paused otherwise.
+    ///
+    /// `SnapshotType::Diff` only writes the pages dirtied since the last snapshot, and
+    /// requires the VM to have been booted with `track_dirty_pages` enabled.
+    pub fn snapshot(
+        &self,
+        mem_path: impl AsRef<Path>,
+        state_path: impl AsRef<Path>,
+        snapshot_type: SnapshotType,
+        resume: bool,
+    ) -> Result<(), SpawnError> {
+        if snapshot_type == SnapshotType::Diff && !self.track_dirty_pages {
+            return Err(SpawnError::DirtyPageTrackingDisabled);
+        }
+        let params = CreateSnapshotParams {
+            snapshot_type,
+            snapshot_path: state_path.as_ref().to_path_buf(),
+            mem_file_path: mem_path.as_ref().to_path_buf(),
+        };
+
+        let mut vmm = self.vmm.lock().unwrap();
+        if !self.paused.load(Ordering::SeqCst) {
+            vmm.pause_vm()?;
+            self.paused.store(true, Ordering::SeqCst);
+        }
+        create_snapshot(&mut vmm, &self.vm_info, &params)?;
+        if resume {
+            vmm.resume_vm()?;
+            self.paused.store(false, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Like [`VmHandle::snapshot`], but compacts the guest first: the balloon is inflated to
+    /// `balloon_mib` with [`VmHandle::inflate_balloon`], waiting up to `timeout`, before the
+    /// VM is paused and its memory written. No snapshot is taken if the guest does not hand
+    /// over that much memory in time.
+    ///
+    /// The pages the balloon holds are left out of the memory file's contents. Requires a
+    /// [`BalloonConfig`](crate::BalloonConfig) with a `stats_polling_interval_s` above 0, and
+    /// the `virtio_balloon` driver in the guest kernel. The balloon stays inflated, in the
+    /// running VM if `resume` is set and in its restored copies; deflate it with
+    /// `balloon_set_target(0)`.
+    pub fn snapshot_compacted(
+        &self,
+        mem_path: impl AsRef<Path>,
+        state_path: impl AsRef<Path>,
+        snapshot_type: SnapshotType,
+        resume: bool,
+        balloon_mib: u32,
+        timeout: Duration,
+    ) -> Result<(), SpawnError> {
+        self.inflate_balloon(balloon_mib, timeout)?;
+        self.snapshot(mem_path, state_path, snapshot_type, resume)
+    }
+
+    /// Takes a `SnapshotType::Diff` snapshot, for checkpointing a long-running guest
+    /// incrementally. Only the pages dirtied since the previous snapshot are written, at their
+    /// offsets in `mem_path`, which is otherwise left sparse; restoring needs the full memory,
+    /// so merge the diff onto the previous memory file first, e.g. with firecracker's
+    /// `snapshot-editor edit-memory rebase`.
+    pub fn snapshot_diff(
+        &self,
+        mem_path: impl AsRef<Path>,
+        state_path: impl AsRef<Path>,
+        resume: bool,
+    ) -> Result<(), SpawnError> {
+        self.snapshot(mem_path, state_path, SnapshotType::Diff, resume)
+    }
+
+    /// Guest-physical address ranges written to since the previous call, or since boot, at
+    /// page granularity. Covers writes by the vcpus and by firecracker's devices.
+    ///
+    /// The VM must be paused, and must have been booted with `track_dirty_pages`. Querying
+    /// resets the dirty log, which diff snapshots share: pages reported here are left out of
+    /// the next `SnapshotType::Diff`, and vice versa. Resuming and pausing again between
+    /// calls yields the pages written while the VM ran.
+    pub fn dirty_pages(&self) -> Result<Vec<Range<u64>>, SpawnError> {
+        if !self.track_dirty_pages {
+            return Err(SpawnError::DirtyPageTrackingDisabled);
+        }
+        let vmm = self.vmm.lock().unwrap();
+        if !self.paused.load(Ordering::SeqCst) {
+            return Err(SpawnError::NotPaused);
+        }
+        // KVM tracks dirty pages per memory slot, one slot per guest memory region
+        let kvm_bitmap = vmm.get_dirty_bitmap()?;
+        // SAFETY: sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for (slot, region) in vmm.guest_memory().iter().enumerate() {
+            let firecracker_bitmap = region.bitmap();
+            for page in 0..region.len() as usize / page_size {
+                let kvm_dirty = kvm_bitmap
+                    .get(&slot)
+                    .is_some_and(|bitmap| bitmap[page / 64] & (1 << (page % 64)) != 0);
+                if !kvm_dirty && !firecracker_bitmap.dirty_at(page * page_size) {
+                    continue;
+                }
+                let start = region.start_addr().0 + (page * page_size) as u64;
+                let end = start + page_size as u64;
+                match ranges.last_mut() {
+                    Some(last) if last.end == start => last.end = end,
+                    _ => ranges.push(start..end),
+                }
+            }
+            if let Some(bitmap) = firecracker_bitmap {
+                bitmap.reset();
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Copies guest memory starting at guest-physical address `addr` into `buf`, e.g. the
+    /// ranges returned by [`VmHandle::dirty_pages`].
+    pub fn read_memory(&self, addr: u64, buf: &mut [u8]) -> Result<(), SpawnError> {
+        self.vmm
+            .lock()
+            .unwrap()
+            .guest_memory()
+            .read_slice(buf, GuestAddress(addr))
+            .map_err(SpawnError::GuestMemory)
+    }
+
+    /// A handle that can stop the VM from another thread, e.g. a supervisor enforcing a
+    /// deadline while this thread blocks in `wait`.
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            vmm: self.vmm.clone(),
+            terminated: self.terminated.clone(),
+        }
+    }
+
+    /// Stops the VM, see [`ShutdownSignal::shutdown`].
+    pub fn shutdown(&self) {
+        self.shutdown_signal().shutdown()
+    }
+
+    /// Same as [`VmHandle::shutdown`]
+    pub fn kill(&self) {
+        self.shutdown()
+    }
+
+    /// Blocks until the guest exits and returns its exit code.
+    pub fn wait(mut self) -> Result<FcExitCode, SpawnError> {
+        let thread = self
+            .thread
+            .take()
+            .expect("event loop thread already joined");
+        let exit_code = thread.join().map_err(|_| SpawnError::EventLoopPanicked)??;
+        if self.terminated.load(Ordering::SeqCst) {
+            return Err(SpawnError::Terminated);
+        }
+        Ok(exit_code)
+    }
+
+    /// The guest's exit code if it has exited, or `None` while it is still running. Fails
+    /// with `SpawnError::Terminated` once the VM was stopped through `shutdown`.
+    ///
+    /// Does not join the event loop thread, so `wait` can still be called afterwards.
+    pub fn try_wait(&self) -> Result<Option<FcExitCode>, SpawnError> {
+        let exit_code = self.vmm.lock().unwrap().shutdown_exit_code();
+        if exit_code.is_some() && self.terminated.load(Ordering::SeqCst) {
+            return Err(SpawnError::Terminated);
+        }
+        Ok(exit_code)
+    }
+
+    /// Like [`VmHandle::wait`], but tells a guest kernel panic apart from a clean shutdown.
+    /// Firecracker cannot: a guest booted with `panic=-1` resets the VM after a panic, which
+    /// exits with `FcExitCode::Ok` just like a poweroff.
+    pub fn wait_reason(self) -> Result<ExitReason, SpawnError> {
+        let (exit_code, panic) = self.wait_for_panic()?;
+        Ok(if panic.is_some() {
+            ExitReason::KernelPanic
+        } else if exit_code == FcExitCode::Ok {
+            ExitReason::Shutdown
+        } else {
+            ExitReason::Failed(exit_code)
+        })
+    }
+
+    /// Like [`VmHandle::wait`], also returning what the guest kernel printed if it panicked
+    pub(crate) fn wait_for_panic(self) -> Result<(FcExitCode, Option<PanicReport>), SpawnError> {
+        let panicked = self.panicked.clone();
+        let exit_code = self.wait()?;
+        Ok((exit_code, panicked.and_then(|p| p.report())))
+    }
+
+    /// Like [`VmHandle::wait`], but yields to the async runtime until the guest exits instead
+    /// of blocking the calling thread. The event loop itself stays on its own thread, as
+    /// firecracker's event manager is not `Send`, and is not driven by the runtime's reactor;
+    /// the runtime is only woken once that thread ends.
+    ///
+    /// Dropping the future stops the VM, so it can be cancelled with `tokio::select!` or
+    /// `tokio::time::timeout`. Joining the event loop thread, on exit or cancellation, runs
+    /// on the runtime's blocking pool.
+    #[cfg(feature = "tokio")]
+    pub async fn wait_async(mut self) -> Result<FcExitCode, SpawnError> {
+        let exited = self.exited.take();
+        let mut handle = DropBlocking(Some(self));
+        if let Some(exited) = exited {
+            // The sender is never used, only dropped
+            let _ = exited.await;
+        }
+        let handle = handle.0.take().expect("handle taken only once");
+        tokio::task::spawn_blocking(move || handle.wait())
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
+
+/// Drops the handle it holds on the async runtime's blocking pool, as stopping the VM joins
+/// its event loop thread
+#[cfg(feature = "tokio")]
+struct DropBlocking(Option<VmHandle>);
+
+#[cfg(feature = "tokio")]
+impl Drop for DropBlocking {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.take() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => drop(runtime.spawn_blocking(move || drop(handle))),
+                Err(_) => drop(handle),
+            }
+        }
+    }
+}
+
+impl Drop for VmHandle {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            {
+                let mut vmm = self.vmm.lock().unwrap();
+                if vmm.shutdown_exit_code().is_none() {
+                    vmm.stop(FcExitCode::Ok);
+                }
+            }
+            let _ = thread.join();
+        }
+        // With the vcpus and the event loop gone nothing writes to the disks anymore; sync what
+        // firecracker wrote so it survives a host crash, whatever the disk's cache type
+        for path in &self.writable_disks {
+            if let Err(e) = File::open(path).and_then(|file| file.sync_all()) {
+                warn!("{}: failed to sync {}: {e}", self.id, path.display());
+            }
+        }
+        // Firecracker leaves the socket behind, and would fail to bind it on the next start
+        if let Some(path) = &self.vsock_path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
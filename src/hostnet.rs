@@ -0,0 +1,316 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+
+use crate::{NetNs, Tap};
+
+/// The sysctl enabling IPv4 forwarding
+pub(crate) const IPV4_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+
+/// The sysctl enabling IPv6 forwarding
+const IPV6_FORWARD: &str = "/proc/sys/net/ipv6/conf/all/forwarding";
+
+/// Forwarding sysctls enabled by live [`Forwarding`]s, with how many share each
+static FORWARDING: Mutex<Vec<Enabled>> = Mutex::new(Vec::new());
+
+struct Enabled {
+    /// Inode of the network namespace the sysctl belongs to, as each has its own
+    netns: u64,
+    path: &'static str,
+    users: usize,
+    /// Opened in `netns`, so the value is restored there whichever thread drops the last user
+    file: File,
+    previous: String,
+}
+
+/// Keeps the forwarding sysctl at `path` enabled, in the network namespace of the thread that
+/// enabled it. Once no `Forwarding` for it is left, it is set back to what it was before the
+/// first one.
+#[derive(Debug)]
+pub(crate) struct Forwarding {
+    netns: u64,
+    path: &'static str,
+}
+
+impl Forwarding {
+    pub(crate) fn enable(path: &'static str) -> io::Result<Forwarding> {
+        let netns = fs::metadata("/proc/thread-self/ns/net")?.ino();
+        let mut enabled = FORWARDING.lock().unwrap();
+        match enabled
+            .iter_mut()
+            .find(|e| e.netns == netns && e.path == path)
+        {
+            Some(e) => e.users += 1,
+            None => {
+                let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+                let mut previous = String::new();
+                file.read_to_string(&mut previous)?;
+                // Sysctls only take writes at offset 0
+                file.write_all_at(b"1\n", 0)?;
+                enabled.push(Enabled {
+                    netns,
+                    path,
+                    users: 1,
+                    file,
+                    previous,
+                });
+            }
+        }
+        Ok(Forwarding { netns, path })
+    }
+}
+
+impl Drop for Forwarding {
+    fn drop(&mut self) {
+        let mut enabled = FORWARDING.lock().unwrap();
+        let Some(i) = enabled
+            .iter()
+            .position(|e| e.netns == self.netns && e.path == self.path)
+        else {
+            return;
+        };
+        enabled[i].users -= 1;
+        if enabled[i].users == 0 {
+            let e = enabled.swap_remove(i);
+            if e.previous.trim() != "1" {
+                if let Err(err) = e.file.write_all_at(e.previous.as_bytes(), 0) {
+                    warn!("failed to restore {}: {err}", e.path);
+                }
+            }
+        }
+    }
+}
+
+/// A TAP interface wired up so the guest can reach other networks, see
+/// [`HostNetwork::bridged`] and [`HostNetwork::nat`]. Needs `CAP_NET_ADMIN`.
+///
+/// Everything set up is torn down on drop, which must happen after the VM has stopped, like
+/// for [`Tap`], see [`VmBuilder::own_host_network`](crate::VmBuilder::own_host_network).
+#[derive(Debug)]
+pub struct HostNetwork {
+    /// The nftables tables holding masquerade rules, as `<family> <name>`
+    nat_tables: Vec<String>,
+    gateway: Option<Ipv4Addr>,
+    gateway6: Option<Ipv6Addr>,
+    tap: Tap,
+    /// Dropped after the masquerade rules are deleted
+    forwarding: Vec<Forwarding>,
+}
+
+impl HostNetwork {
+    /// Creates the TAP interface `tap_name` and adds it to the existing bridge `bridge`. The
+    /// guest gets its address the way other hosts on the bridge do, e.g. from DHCP.
+    pub fn bridged(tap_name: &str, bridge: &str) -> io::Result<HostNetwork> {
+        let tap = Tap::create(tap_name, None)?;
+        tap.add_to_bridge(bridge)?;
+        Ok(HostNetwork {
+            nat_tables: Vec::new(),
+            gateway: None,
+            gateway6: None,
+            tap,
+            forwarding: Vec::new(),
+        })
+    }
+
+    /// Creates the TAP interface `tap_name` on the subnet `subnet/prefix_len` and masquerades
+    /// the guest's traffic to other networks behind the host's addresses.
+    ///
+    /// The host takes the subnet's first address, the guest's gateway (see
+    /// [`HostNetwork::gateway`]); give the guest another one, e.g. with an `ip=` kernel
+    /// parameter. IP forwarding is enabled host-wide, or namespace-wide when created inside a
+    /// [`NetNs`], until every `HostNetwork` and [`Veth`](crate::Veth) that needed it is
+    /// dropped; it is then set back to what it was. The masquerade rule is added with the
+    /// `nft` command, in a table of its own.
+    pub fn nat(tap_name: &str, subnet: Ipv4Addr, prefix_len: u8) -> io::Result<HostNetwork> {
+        if prefix_len > 30 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("subnet /{prefix_len} has no room for a host and a guest"),
+            ));
+        }
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0);
+        let network = Ipv4Addr::from(u32::from(subnet) & mask);
+        let gateway = Ipv4Addr::from(u32::from(network) + 1);
+        let tap = Tap::create(tap_name, None)?;
+        tap.set_address(gateway, prefix_len)?;
+        let mut net = HostNetwork {
+            nat_tables: Vec::new(),
+            gateway: Some(gateway),
+            gateway6: None,
+            tap,
+            forwarding: vec![Forwarding::enable(IPV4_FORWARD)?],
+        };
+        net.masquerade("ip", &format!("{network}/{prefix_len}"))?;
+        Ok(net)
+    }
+
+    /// Also masquerades the guest's IPv6 traffic from the subnet `subnet/prefix_len`, e.g. a
+    /// unique local `fd00::/64`, for dual-stack guests. The host takes the subnet's first
+    /// address, see [`HostNetwork::gateway6`].
+    ///
+    /// The kernel has no `ip=` for IPv6: configure the guest's address and default route from
+    /// its userspace. IPv6 forwarding is enabled like IPv4 forwarding for
+    /// [`HostNetwork::nat`], and set back once no longer needed; with forwarding on, the
+    /// host's own interfaces stop accepting router advertisements unless their `accept_ra`
+    /// is 2.
+    pub fn nat6(&mut self, subnet: Ipv6Addr, prefix_len: u8) -> io::Result<()> {
+        if prefix_len > 126 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("subnet /{prefix_len} has no room for a host and a guest"),
+            ));
+        }
+        let mask = u128::MAX
+            .checked_shl(128 - u32::from(prefix_len))
+            .unwrap_or(0);
+        let network = Ipv6Addr::from(u128::from(subnet) & mask);
+        let gateway = Ipv6Addr::from(u128::from(network) + 1);
+        self.tap.set_address6(gateway, prefix_len)?;
+        self.forwarding.push(Forwarding::enable(IPV6_FORWARD)?);
+        self.gateway6 = Some(gateway);
+        self.masquerade("ip6", &format!("{network}/{prefix_len}"))
+    }
+
+    fn masquerade(&mut self, family: &str, subnet: &str) -> io::Result<()> {
+        let table = masquerade(family, subnet, self.tap.name())?;
+        self.nat_tables.push(table);
+        Ok(())
+    }
+
+    /// The interface to put in [`NetConfig::tap_iface_name`](crate::NetConfig::tap_iface_name)
+    pub fn tap(&self) -> &Tap {
+        &self.tap
+    }
+
+    /// The host's address on the subnet, for NAT
+    pub fn gateway(&self) -> Option<Ipv4Addr> {
+        self.gateway
+    }
+
+    /// The host's address on the IPv6 subnet, once [`HostNetwork::nat6`] set it up
+    pub fn gateway6(&self) -> Option<Ipv6Addr> {
+        self.gateway6
+    }
+}
+
+impl Drop for HostNetwork {
+    fn drop(&mut self) {
+        for table in &self.nat_tables {
+            if let Err(e) = nft(&format!("delete table {table}")) {
+                warn!("failed to delete nftables table {table}: {e}");
+            }
+        }
+    }
+}
+
+/// A [`Tap`] or [`HostNetwork`] handed over to a [`Vm`](crate::Vm), see
+/// [`VmBuilder::own_tap`](crate::VmBuilder::own_tap). The handles of the VM's boots share
+/// it, so it is torn down once the `Vm` and all its handles are dropped, after the VM has
+/// stopped. It is dropped inside `netns`, where it was created, if given.
+#[derive(Clone, Debug)]
+pub struct OwnedNet(Arc<Owned>);
+
+#[derive(Debug)]
+struct Owned {
+    netns: Option<NetNs>,
+    net: Option<Net>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Net {
+    Tap(Tap),
+    HostNetwork(HostNetwork),
+}
+
+impl OwnedNet {
+    pub fn tap(tap: Tap, netns: Option<NetNs>) -> OwnedNet {
+        OwnedNet::new(Net::Tap(tap), netns)
+    }
+
+    pub fn host_network(net: HostNetwork, netns: Option<NetNs>) -> OwnedNet {
+        OwnedNet::new(Net::HostNetwork(net), netns)
+    }
+
+    /// The name of the TAP interface, to put in a [`NetConfig`](crate::NetConfig)
+    pub fn tap_name(&self) -> &str {
+        match self.0.net.as_ref().expect("only taken on drop") {
+            Net::Tap(tap) => tap.name(),
+            Net::HostNetwork(net) => net.tap().name(),
+        }
+    }
+
+    pub(crate) fn new(net: Net, netns: Option<NetNs>) -> OwnedNet {
+        OwnedNet(Arc::new(Owned {
+            netns,
+            net: Some(net),
+        }))
+    }
+}
+
+impl Drop for Owned {
+    fn drop(&mut self) {
+        let net = self.net.take();
+        match &self.netns {
+            Some(netns) => {
+                if let Err(e) = netns.run(move || drop(net)) {
+                    warn!("failed to enter the network namespace to tear down: {e}");
+                }
+            }
+            None => drop(net),
+        }
+    }
+}
+
+/// Masquerades traffic from `subnet` leaving through interfaces other than `iface`, in a
+/// table of its own in the nftables address `family`. Returns the table, as
+/// `<family> <name>`.
+pub(crate) fn masquerade(family: &str, subnet: &str, iface: &str) -> io::Result<String> {
+    // Interface names may contain characters nft identifiers cannot
+    let table: String = format!("firecracker_spawn_{iface}")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let saddr = if family == "ip6" {
+        "ip6 saddr"
+    } else {
+        "ip saddr"
+    };
+    nft(&format!(
+        "table {family} {table} {{
+            chain postrouting {{
+                type nat hook postrouting priority srcnat; policy accept;
+                {saddr} {subnet} oifname != \"{iface}\" masquerade
+            }}
+        }}"
+    ))?;
+    Ok(format!("{family} {table}"))
+}
+
+/// Runs the nftables `script`
+pub(crate) fn nft(script: &str) -> io::Result<()> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(script.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Write};
+
+use cpio::newc::{self, ModeFileType, NewcBuilder};
+
+use crate::memfd;
+
+/// Assembles an initramfs, a newc cpio archive, for [`VmBuilder::initrd`](crate::VmBuilder::initrd).
+///
+/// Parent directories of every entry are added as needed, so
+/// `.file("/etc/app/config.json", ..)` also creates `/etc` and `/etc/app`.
+#[derive(Clone, Default)]
+pub struct InitrdBuilder {
+    entries: Vec<(String, Option<Vec<u8>>, u32)>,
+    #[cfg(feature = "gzip")]
+    gzip: bool,
+}
+
+impl InitrdBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a regular file at `guest_path` with permission bits `mode`, e.g. `0o644`
+    pub fn file(mut self, guest_path: &str, contents: impl Into<Vec<u8>>, mode: u32) -> Self {
+        self.entries
+            .push((normalize(guest_path), Some(contents.into()), mode));
+        self
+    }
+
+    /// Adds `/init`, the program the kernel runs from an initramfs unless the cmdline sets
+    /// `rdinit=`
+    pub fn init(self, contents: impl Into<Vec<u8>>) -> Self {
+        self.file("init", contents, 0o755)
+    }
+
+    /// Adds an empty directory, e.g. a mount point such as `/proc`
+    pub fn dir(mut self, guest_path: &str, mode: u32) -> Self {
+        self.entries.push((normalize(guest_path), None, mode));
+        self
+    }
+
+    /// Compresses the archive with gzip, which the kernel unpacks when built with
+    /// `CONFIG_RD_GZIP`
+    #[cfg(feature = "gzip")]
+    pub fn gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    /// Writes the archive, trailer included, to `out`
+    pub fn write(&self, out: impl Write) -> io::Result<()> {
+        #[cfg(feature = "gzip")]
+        if self.gzip {
+            let mut out = flate2::write::GzEncoder::new(out, flate2::Compression::default());
+            self.write_cpio(&mut out)?;
+            out.finish()?;
+            return Ok(());
+        }
+        self.write_cpio(out)
+    }
+
+    /// Writes the archive to an in-memory file, ready to pass to `VmBuilder::initrd`
+    pub fn build(&self) -> io::Result<File> {
+        let mut archive = Vec::new();
+        self.write(&mut archive)?;
+        memfd("initrd", archive.as_slice())
+    }
+
+    fn write_cpio(&self, mut out: impl Write) -> io::Result<()> {
+        let mut dirs = BTreeSet::new();
+        let mut ino = 0;
+        for (path, contents, mode) in &self.entries {
+            let parents = path.match_indices('/').map(|(i, _)| &path[..i]);
+            let own_dir = contents.is_none().then_some(path.as_str());
+            for dir in parents.chain(own_dir) {
+                if !dirs.insert(dir.to_string()) {
+                    continue;
+                }
+                ino += 1;
+                let mode = if Some(dir) == own_dir { *mode } else { 0o755 };
+                NewcBuilder::new(dir)
+                    .ino(ino)
+                    .nlink(2)
+                    .mode(mode)
+                    .set_mode_file_type(ModeFileType::Directory)
+                    .write(&mut out, 0)
+                    .finish()?;
+            }
+            if let Some(contents) = contents {
+                ino += 1;
+                let mut entry = NewcBuilder::new(path)
+                    .ino(ino)
+                    .nlink(1)
+                    .mode(*mode)
+                    .set_mode_file_type(ModeFileType::Regular)
+                    .write(&mut out, contents.len() as u32);
+                entry.write_all(contents)?;
+                entry.finish()?;
+            }
+        }
+        newc::trailer(&mut out)?;
+        out.flush()
+    }
+}
+
+/// cpio entries are relative to the root of the initramfs
+fn normalize(guest_path: &str) -> String {
+    guest_path.trim_matches('/').to_string()
+}
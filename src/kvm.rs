@@ -0,0 +1,47 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::os::fd::AsRawFd;
+
+use crate::SpawnError;
+
+/// `_IO(KVMIO, 0x00)`
+const KVM_GET_API_VERSION: libc::c_ulong = 0xae00;
+/// `_IO(KVMIO, 0x03)`
+const KVM_CHECK_EXTENSION: libc::c_ulong = 0xae03;
+
+/// Capabilities firecracker cannot boot a VM without
+const REQUIRED_CAPS: [(u32, &str); 4] = [
+    (kvm_bindings::KVM_CAP_IRQCHIP, "KVM_CAP_IRQCHIP"),
+    (kvm_bindings::KVM_CAP_IOEVENTFD, "KVM_CAP_IOEVENTFD"),
+    (kvm_bindings::KVM_CAP_IRQFD, "KVM_CAP_IRQFD"),
+    (kvm_bindings::KVM_CAP_USER_MEMORY, "KVM_CAP_USER_MEMORY"),
+];
+
+/// Checks that `/dev/kvm` can be opened and offers what firecracker needs.
+pub(crate) fn check() -> Result<(), SpawnError> {
+    let kvm = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+        .map_err(SpawnError::KvmUnavailable)?;
+
+    // SAFETY: neither ioctl takes an argument, `kvm` is an open KVM fd.
+    let version = unsafe { libc::ioctl(kvm.as_raw_fd(), KVM_GET_API_VERSION as _) };
+    if version != kvm_bindings::KVM_API_VERSION as i32 {
+        return Err(SpawnError::KvmUnavailable(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unsupported KVM API version {version}"),
+        )));
+    }
+    for (cap, name) in REQUIRED_CAPS {
+        // SAFETY: as above; the argument is passed by value.
+        let ret = unsafe { libc::ioctl(kvm.as_raw_fd(), KVM_CHECK_EXTENSION as _, cap) };
+        if ret <= 0 {
+            return Err(SpawnError::KvmUnavailable(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("missing capability {name}"),
+            )));
+        }
+    }
+    Ok(())
+}
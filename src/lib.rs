@@ -1,307 +1,4156 @@
-use std::error::Error;
-use std::fs::File;
-use std::path::PathBuf;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::{debug, info};
+use seccompiler::BpfThreadMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use utils::net::mac::MacAddr;
-use vmm::builder::build_microvm_for_boot;
+use uuid::Uuid;
+use vmm::cpu_config::templates::{CpuTemplateType, CustomCpuTemplate, StaticCpuTemplate};
 pub use vmm::devices::legacy::serial::SerialOut;
-use vmm::devices::virtio::block::CacheType;
+pub use vmm::devices::virtio::balloon::BalloonStats;
+/// Host page cache behaviour for a block device.
+///
+/// - `Unsafe`: guest flush requests are ignored. Fast, but data written by the guest may be
+///   lost if the host crashes.
+/// - `Writeback`: guest flush requests are honored with an `fsync` on the backing file, so
+///   data the guest has flushed survives a host crash.
+pub use vmm::devices::virtio::block::CacheType;
+pub use vmm::mmds::data_store::MmdsVersion;
 use vmm::resources::VmResources;
-use vmm::seccomp_filters::get_empty_filters;
+use vmm::seccomp_filters::{get_filters, SeccompConfig};
+use vmm::vmm_config::balloon::{BalloonBuilder, BalloonDeviceConfig};
 use vmm::vmm_config::boot_source::{BootConfig, BootSource, BootSourceConfig};
+pub use vmm::vmm_config::drive::FileEngineType;
 use vmm::vmm_config::drive::{BlockBuilder, BlockDeviceConfig};
-use vmm::vmm_config::instance_info::{InstanceInfo, VmState};
+use vmm::vmm_config::entropy::{EntropyDeviceBuilder, EntropyDeviceConfig};
+use vmm::vmm_config::instance_info::InstanceInfo;
+pub use vmm::vmm_config::instance_info::VmState;
 use vmm::vmm_config::machine_config::HugePageConfig;
-use vmm::vmm_config::machine_config::VmConfig;
+use vmm::vmm_config::machine_config::{VmConfig, MAX_SUPPORTED_VCPUS};
+use vmm::vmm_config::mmds::MmdsConfig as VmmMmdsConfig;
 use vmm::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
+pub use vmm::vmm_config::snapshot::SnapshotType;
+use vmm::vmm_config::snapshot::{LoadSnapshotParams, MemBackendConfig, MemBackendType};
 use vmm::vmm_config::vsock::{VsockBuilder, VsockDeviceConfig};
-use vmm::{EventManager, FcExitCode};
+use vmm::vmm_config::{RateLimiterConfig, TokenBucketConfig};
+pub use vmm::FcExitCode;
 
-#[derive(Clone)]
+/// Kernel command line size limit, including the trailing NUL
+const DEFAULT_CMDLINE_CAPACITY: usize = 4096;
+
+/// Cmdline parameter for Ubuntu's `overlayroot` initramfs hook, mounting the writable disk of
+/// [`VmBuilder::overlay_rootfs`] over the read-only rootfs
+pub const OVERLAYROOT_PARAM: &str = "overlayroot=device:dev=/dev/vdb";
+
+mod affinity;
+mod boottime;
+mod builder;
+mod cid;
+mod cmdline;
+mod config;
+mod error;
+mod handle;
+mod hostnet;
+mod initrd;
+mod kvm;
+mod memfd;
+mod meminfo;
+mod metrics;
+mod mountns;
+mod netns;
+mod pty;
+mod serial;
+mod tap;
+mod template;
+mod veth;
+pub use boottime::init_logger;
+pub use builder::VmBuilder;
+use cid::{CidLease, FIRST_GUEST_CID};
+pub use cmdline::CmdlineBuilder;
+pub use error::{
+    BalloonConfigError, BalloonError, CmdlineError, CreateSnapshotError, DriveError,
+    EntropyDeviceError, EventManagerError, GuestMemoryError, MetricsError, MmdsConfigError,
+    MmdsDatastoreError, NetworkInterfaceError, RestoreFromSnapshotError, SeccompFilterError,
+    SeccompInstallError, SpawnError, StartMicrovmError, VmmError, VsockConfigError,
+};
+pub use handle::{ShutdownSignal, VcpuCpuTime, VmHandle, VmInfo};
+pub use hostnet::{HostNetwork, OwnedNet};
+pub use initrd::InitrdBuilder;
+pub use memfd::memfd;
+pub use metrics::init_metrics;
+pub use netns::NetNs;
+pub use pty::PtySerialOut;
+/// [`VmHandle::wait_for_serial`] takes its `Regex`
+pub use regex;
+/// `MmdsConfig::data` and custom CPU template errors use its types
+pub use serde_json;
+use serial::{
+    ConsoleTail, PanicReport, Ready, ReadyWatcher, SerialMatcher, SerialWaiters, SharedSerialOut,
+    TailWatcher,
+};
+pub use serial::{LineSerialOut, PrefixSerialOut, TeeSerialOut};
+pub use tap::Tap;
+pub use template::Template;
+pub use veth::Veth;
+
+/// A token bucket: holds up to `size` tokens (bytes or operations) and is refilled with
+/// `size` tokens every `refill_time_ms`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TokenBucket {
+    pub size: u64,
+    /// Extra tokens available once, on top of `size`, e.g. to let a boot go by unthrottled
+    pub one_time_burst: Option<u64>,
+    pub refill_time_ms: u64,
+}
+
+/// Bandwidth (bytes) and ops limits of a device; a missing bucket leaves that unlimited.
+/// The same limit can be given to disks, network interfaces and the entropy device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bandwidth: Option<TokenBucket>,
+    pub ops: Option<TokenBucket>,
+}
+
+impl From<TokenBucket> for TokenBucketConfig {
+    fn from(bucket: TokenBucket) -> Self {
+        TokenBucketConfig {
+            size: bucket.size,
+            one_time_burst: bucket.one_time_burst,
+            refill_time: bucket.refill_time_ms,
+        }
+    }
+}
+
+impl From<TokenBucketConfig> for TokenBucket {
+    fn from(bucket: TokenBucketConfig) -> Self {
+        TokenBucket {
+            size: bucket.size,
+            one_time_burst: bucket.one_time_burst,
+            refill_time_ms: bucket.refill_time,
+        }
+    }
+}
+
+impl From<RateLimit> for RateLimiterConfig {
+    fn from(limit: RateLimit) -> Self {
+        RateLimiterConfig {
+            bandwidth: limit.bandwidth.map(Into::into),
+            ops: limit.ops.map(Into::into),
+        }
+    }
+}
+
+impl From<RateLimiterConfig> for RateLimit {
+    fn from(limit: RateLimiterConfig) -> Self {
+        RateLimit {
+            bandwidth: limit.bandwidth.map(Into::into),
+            ops: limit.ops.map(Into::into),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct Disk {
+    /// Backing file on the host. Leave empty when `socket` is set
     pub path: PathBuf,
+    /// On the root disk this also puts `ro` (or `rw` when unset) on the kernel cmdline
     pub read_only: bool,
+    /// Defaults to `CacheType::Writeback` for writable disks, so data the guest flushed
+    /// survives a host crash, and to the faster `CacheType::Unsafe` for read-only ones
+    pub cache_type: Option<CacheType>,
+    /// Bandwidth (bytes) and ops (requests) limits for this disk
+    pub rate_limiter: Option<RateLimit>,
+    /// IO engine for the backing file - Leave blank for firecracker's default (`Sync`).
+    /// `Async` requires io_uring support in the host kernel; if it is missing, starting the
+    /// VM fails with `SpawnError::Disk`.
+    pub file_engine_type: Option<FileEngineType>,
+    /// Unique id of the partition to boot from when this disk is the rootfs; firecracker
+    /// then passes `root=PARTUUID=<partuuid>` to the kernel instead of `root=/dev/vda`
+    pub partuuid: Option<String>,
+    /// Unix socket of a vhost-user block backend serving this disk, instead of `path`.
+    /// `read_only`, `rate_limiter` and `file_engine_type` do not apply to such disks.
+    pub socket: Option<PathBuf>,
+    /// Boot from this disk although it is one of the `extra_disks`. At most one disk can be
+    /// the root device; `rootfs` always is, so this cannot be combined with it
+    pub is_root: bool,
+}
+
+impl Disk {
+    fn cache_type(&self) -> CacheType {
+        match self.cache_type {
+            Some(cache_type) => cache_type,
+            None if self.read_only => CacheType::Unsafe,
+            None => CacheType::Writeback,
+        }
+    }
+
+    /// A disk backed by an already open file, e.g. one from [`memfd`]. Firecracker reopens
+    /// it through `/proc/self/fd`, so `file` must stay open until the VM has started.
+    pub fn from_file(file: &File) -> Disk {
+        Disk {
+            path: PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd())),
+            ..Default::default()
+        }
+    }
+}
+
+/// Where to load the kernel image from
+pub enum KernelSource {
+    /// Opened when the VM is started
+    Path(PathBuf),
+    /// An already opened image, cloned when the VM is started
+    File(File),
+}
+
+impl KernelSource {
+    fn open(&self) -> Result<File, SpawnError> {
+        match self {
+            KernelSource::Path(path) => File::open(path).map_err(|source| SpawnError::Kernel {
+                path: Some(path.clone()),
+                source,
+            }),
+            KernelSource::File(file) => file
+                .try_clone()
+                .map_err(|source| SpawnError::Kernel { path: None, source }),
+        }
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        match self {
+            KernelSource::Path(path) => Some(path.clone()),
+            KernelSource::File(_) => None,
+        }
+    }
+}
+
+impl From<PathBuf> for KernelSource {
+    fn from(path: PathBuf) -> Self {
+        KernelSource::Path(path)
+    }
+}
+
+impl From<&Path> for KernelSource {
+    fn from(path: &Path) -> Self {
+        KernelSource::Path(path.to_path_buf())
+    }
+}
+
+impl From<File> for KernelSource {
+    fn from(file: File) -> Self {
+        KernelSource::File(file)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct NetConfig {
+    /// Name of an unused TAP interface on the host, must exist (`ip tuntap add <name> mode tap`)
+    pub tap_iface_name: String,
+    /// Mac address - Leave blank for [`NetConfig::default_mac`]. [`NetConfig::parse_mac`]
+    /// converts from the usual `52:54:00:12:34:56` notation
+    pub vm_mac: Option<[u8; 6]>,
+    /// Bandwidth (bytes) and ops (packets) limits for traffic received by the guest
+    pub rx_rate_limiter: Option<RateLimit>,
+    /// Bandwidth (bytes) and ops (packets) limits for traffic sent by the guest
+    pub tx_rate_limiter: Option<RateLimit>,
+    /// Serve [`Vm::mmds`] on this interface. MMDS is only served on the interfaces that opt
+    /// in, and requires at least one
+    pub allow_mmds: bool,
+}
+
+impl NetConfig {
+    /// Parses a MAC address written as six colon-separated hex octets
+    pub fn parse_mac(mac: &str) -> Result<[u8; 6], SpawnError> {
+        let invalid = || SpawnError::InvalidMac {
+            mac: mac.to_string(),
+        };
+        let parsed = MacAddr::parse_str(mac).map_err(|_| invalid())?;
+        parsed.get_bytes().try_into().map_err(|_| invalid())
+    }
+
+    /// The MAC given to interface number `index` of the VM with `instance_id` when `vm_mac`
+    /// is not set: `02` followed by the first five bytes of the 64-bit FNV-1a hash of the
+    /// instance id's bytes and then `index` as a little-endian `u32`. The leading `02` marks
+    /// it as a locally administered unicast address.
+    pub fn default_mac(instance_id: &str, index: u32) -> [u8; 6] {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for b in instance_id.bytes().chain(index.to_le_bytes()) {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        let h = hash.to_be_bytes();
+        [0x02, h[0], h[1], h[2], h[3], h[4]]
+    }
+}
+
+/// A virtio-vsock device, connecting the guest to Unix sockets on the host.
+///
+/// Firecracker's vsock device has nothing to tune: its queues have a fixed size, and it runs
+/// on the VM's event loop thread like every other device. A connection only moves as fast as
+/// the host side reads it, so serve each accepted connection on its own thread rather than
+/// one after the other.
+#[derive(Clone)]
+pub struct VsockConfig {
+    /// Path of the Unix domain socket the host connects to the guest through. Firecracker
+    /// binds it itself and cannot be handed an existing socket, so it must not exist yet and
+    /// its directory must be writable; it is removed once the VM is stopped.
+    pub uds_path: String,
+    /// Context id the guest is reachable at, 3 or higher. Leave blank to use the lowest one
+    /// not taken by another VM started by this process, see [`VmHandle::guest_cid`]
+    pub guest_cid: Option<u32>,
+}
+
+impl VsockConfig {
+    /// A vsock device at `uds_path` with an automatically assigned guest CID
+    pub fn new(uds_path: impl Into<String>) -> Self {
+        VsockConfig {
+            uds_path: uds_path.into(),
+            guest_cid: None,
+        }
+    }
+
+    /// Path firecracker forwards guest connections to host `port` to, `<uds_path>_<port>`.
+    /// The crate never creates or removes these; bind them with [`VsockConfig::listen`], or
+    /// any other way, e.g. through systemd socket activation.
+    pub fn listener_path(&self, port: u32) -> PathBuf {
+        PathBuf::from(format!("{}_{}", self.uds_path, port))
+    }
+
+    /// Wraps an inherited listening socket, e.g. one passed in by systemd, for guest
+    /// connections to host `port`. Fails with `InvalidInput` unless it is bound at
+    /// [`VsockConfig::listener_path`], where firecracker will connect to it.
+    pub fn listener_from_fd(&self, port: u32, fd: OwnedFd) -> io::Result<UnixListener> {
+        let listener = UnixListener::from(fd);
+        let expected = self.listener_path(port);
+        let addr = listener.local_addr()?;
+        if addr.as_pathname() != Some(expected.as_path()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "socket is bound at {:?}, firecracker connects to {}",
+                    addr.as_pathname(),
+                    expected.display()
+                ),
+            ));
+        }
+        Ok(listener)
+    }
+
+    /// Connects to guest `port` through `uds_path`, doing firecracker's `CONNECT <port>`
+    /// handshake. Fails with `ConnectionRefused` if nothing listens on `port` in the guest
+    /// yet. Guest connections to the host go the other way, see [`VsockConfig::listen`].
+    pub fn host_connect(&self, port: u32) -> io::Result<UnixStream> {
+        let mut stream = UnixStream::connect(&self.uds_path)?;
+        stream.write_all(format!("CONNECT {port}\n").as_bytes())?;
+        // Firecracker acknowledges with `OK <host port>\n`; read it byte by byte so no guest
+        // data is consumed
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        while byte[0] != b'\n' {
+            if stream.read(&mut byte)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("nothing listens on guest vsock port {port}"),
+                ));
+            }
+            reply.push(byte[0]);
+        }
+        if !reply.starts_with(b"OK ") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unexpected vsock handshake reply {:?}",
+                    String::from_utf8_lossy(&reply)
+                ),
+            ));
+        }
+        Ok(stream)
+    }
+
+    /// Listens for guest connections to host `port`, replacing a stale socket file left by a
+    /// previous run. Bind every port the guest connects to before starting the VM; a
+    /// connection to a port nobody listens on is reset.
+    pub fn listen(&self, port: u32) -> io::Result<UnixListener> {
+        let path = self.listener_path(port);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        UnixListener::bind(path)
+    }
+}
+
+/// A virtio-rng device, exposed to the guest as `/dev/hwrng`
+#[derive(Clone, Default)]
+pub struct EntropyConfig {
+    /// Bandwidth (bytes) and ops (requests) limits for entropy handed to the guest
+    pub rate_limiter: Option<RateLimit>,
+}
+
+/// A virtio-balloon device, used to reclaim guest memory at runtime. The guest kernel needs
+/// the `virtio_balloon` driver (`CONFIG_VIRTIO_BALLOON`), otherwise the balloon never inflates.
+#[derive(Clone, Default)]
+pub struct BalloonConfig {
+    /// Initial balloon size; this much memory is taken away from the guest
+    pub amount_mib: u32,
+    /// Let the guest deflate the balloon when it runs out of memory
+    pub deflate_on_oom: bool,
+    /// How often the guest reports memory statistics, 0 disables them. Required by
+    /// [`VmHandle::balloon_stats`] and [`VmHandle::inflate_balloon`]
+    pub stats_polling_interval_s: u16,
+}
+
+/// Pages backing guest memory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HugePages {
+    /// Regular 4 KiB pages
+    #[default]
+    None,
+    Hugetlbfs2M,
+    /// Not supported by the bundled firecracker, [`Vm::validate`] rejects it
+    Hugetlbfs1G,
+}
+
+impl HugePages {
+    fn page_size_mib(self) -> Option<usize> {
+        match self {
+            HugePages::None => None,
+            HugePages::Hugetlbfs2M => Some(2),
+            HugePages::Hugetlbfs1G => Some(1024),
+        }
+    }
+
+    fn to_vmm(self) -> Result<HugePageConfig, SpawnError> {
+        match self {
+            HugePages::None => Ok(HugePageConfig::None),
+            HugePages::Hugetlbfs2M => Ok(HugePageConfig::Hugetlbfs2M),
+            HugePages::Hugetlbfs1G => Err(SpawnError::UnsupportedHugePages {
+                page_size_mib: 1024,
+            }),
+        }
+    }
+}
+
+/// Syscall filters installed on the VMM's vcpu and event loop threads
+#[derive(Clone, Default)]
+pub enum Seccomp {
+    /// Firecracker's default filters for this architecture
+    #[default]
+    Default,
+    /// Filters compiled with `seccompiler-bin` into a BPF file
+    Custom(PathBuf),
+    /// No filtering at all. A guest that escapes into the VMM gets unrestricted access to
+    /// the host kernel, so this should only be used for local experimentation.
+    None,
+}
+
+impl Seccomp {
+    fn filters(&self) -> Result<BpfThreadMap, SpawnError> {
+        let config = match self {
+            Seccomp::Default => SeccompConfig::Advanced,
+            Seccomp::Custom(path) => SeccompConfig::Custom(File::open(path).map_err(|source| {
+                SpawnError::SeccompFile {
+                    path: path.clone(),
+                    source,
+                }
+            })?),
+            Seccomp::None => SeccompConfig::None,
+        };
+        get_filters(config).map_err(SpawnError::Seccomp)
+    }
+}
+
+/// CPUID/MSR masking applied to the guest vcpus
+#[derive(Clone)]
+pub enum CpuTemplate {
+    /// One of firecracker's built-in templates by name, e.g. `T2` or `C3` on x86_64 and
+    /// `V1N1` on aarch64
+    Static(String),
+    /// A custom template in firecracker's JSON format
+    Custom(PathBuf),
+}
+
+impl CpuTemplate {
+    fn to_vmm(&self) -> Result<CpuTemplateType, SpawnError> {
+        match self {
+            CpuTemplate::Static(name) => {
+                serde_json::from_value::<StaticCpuTemplate>(serde_json::Value::String(name.clone()))
+                    .map(CpuTemplateType::Static)
+                    .map_err(|_| SpawnError::UnknownCpuTemplate { name: name.clone() })
+            }
+            CpuTemplate::Custom(path) => {
+                let json = std::fs::read(path).map_err(|source| SpawnError::CpuTemplateFile {
+                    path: path.clone(),
+                    source,
+                })?;
+                serde_json::from_slice::<CustomCpuTemplate>(&json)
+                    .map(CpuTemplateType::Custom)
+                    .map_err(|source| SpawnError::CpuTemplateParse {
+                        path: path.clone(),
+                        source,
+                    })
+            }
+        }
+    }
+}
+
+/// The instance metadata service, reachable by the guest over its network interface
+#[derive(Clone)]
+pub struct MmdsConfig {
+    pub version: MmdsVersion,
+    /// Address the guest reaches MMDS at - Leave blank for firecracker's default (169.254.169.254)
+    pub ipv4_address: Option<Ipv4Addr>,
+    /// Initial contents of the data store; must be a JSON object
+    pub data: Option<serde_json::Value>,
+}
+
+impl MmdsConfig {
+    /// Replaces the initial contents of the data store, e.g. a cloud-init style
+    /// `{"latest": {"meta-data": {..}}}` document. Fails unless `data` is a JSON object.
+    pub fn set_json(&mut self, data: serde_json::Value) -> Result<(), SpawnError> {
+        if !data.is_object() {
+            return Err(SpawnError::MmdsNotObject {
+                key: "/".to_string(),
+            });
+        }
+        self.data = Some(data);
+        Ok(())
+    }
+
+    /// Serves the contents of the file at `path` as `latest/user-data`, keeping the rest of
+    /// the data store. MMDS only holds strings, so binary user-data must be base64-encoded,
+    /// and the guest decode it; text files can be stored as is.
+    pub fn set_user_data(
+        &mut self,
+        path: impl AsRef<Path>,
+        base64: bool,
+    ) -> Result<(), SpawnError> {
+        let path = path.as_ref();
+        let read_error = |source| SpawnError::MmdsUserData {
+            path: path.to_path_buf(),
+            source,
+        };
+        let contents = fs::read(path).map_err(read_error)?;
+        let user_data = if base64 {
+            BASE64_STANDARD.encode(contents)
+        } else {
+            String::from_utf8(contents)
+                .map_err(|e| read_error(io::Error::new(io::ErrorKind::InvalidData, e)))?
+        };
+
+        let not_object = |key: &str| SpawnError::MmdsNotObject {
+            key: key.to_string(),
+        };
+        let data = self
+            .data
+            .get_or_insert_with(|| serde_json::json!({}))
+            .as_object_mut()
+            .ok_or_else(|| not_object("/"))?;
+        let latest = data
+            .entry("latest")
+            .or_insert_with(|| serde_json::json!({}))
+            .as_object_mut()
+            .ok_or_else(|| not_object("/latest"))?;
+        latest.insert("user-data".to_string(), user_data.into());
+        Ok(())
+    }
+}
+
+impl Default for MmdsConfig {
+    fn default() -> Self {
+        MmdsConfig {
+            version: MmdsVersion::V2,
+            ipv4_address: None,
+            data: None,
+        }
+    }
+}
+
+/// What [`Vm::make`] does when the guest exits on its own.
+///
+/// Firecracker has no ACPI, so it cannot tell a guest `reboot` from a `poweroff`, or from a
+/// kernel panic with `panic=-1`: all of them reset the VM and end with `FcExitCode::Ok`
+/// (see [`VmHandle::wait_reason`]). Restarting therefore applies to any clean guest exit.
+/// Timeouts, shutdowns from the host and VMM failures are never restarted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RebootPolicy {
+    /// Return the exit code
+    #[default]
+    Exit,
+    /// Boot the VM again from the same configuration, indefinitely
+    Restart,
+    /// Boot the VM again at most this many times, then return the exit code
+    RestartUpTo(u32),
+}
+
+impl RebootPolicy {
+    fn restarts(self, exit_code: FcExitCode, restarts: u32) -> bool {
+        exit_code == FcExitCode::Ok
+            && match self {
+                RebootPolicy::Exit => false,
+                RebootPolicy::Restart => true,
+                RebootPolicy::RestartUpTo(max) => restarts < max,
+            }
+    }
+}
+
+/// Why a VM exited, see [`VmHandle::wait_reason`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The guest powered off or rebooted
+    Shutdown,
+    /// The guest kernel panicked. With `panic=0` the guest would hang, so it is stopped
+    KernelPanic,
+    /// Firecracker exited with an error, e.g. `FcExitCode::BadConfiguration`
+    Failed(FcExitCode),
+}
+
+/// How a VM run by [`Vm::run`] ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub exit_code: FcExitCode,
+    /// Wall-clock time from the first boot until the guest last exited, restarts included
+    pub run_duration: Duration,
+}
+
+/// Options for [`Vm::from_snapshot`]
+#[derive(Clone, Default)]
+pub struct RestoreConfig {
+    /// Replacement backing files for block devices, as `(drive_id, path)`. The paths
+    /// recorded in the snapshot are reopened during the restore and must still exist; these
+    /// are swapped in before the VM resumes.
+    pub disk_paths: Vec<(String, PathBuf)>,
+    /// Keep tracking dirty pages so diff snapshots can be taken of the restored VM
+    pub track_dirty_pages: bool,
+    /// Defaults to a random UUID
+    pub instance_id: Option<String>,
+    pub seccomp: Seccomp,
+    /// See [`Vm::timeout`]
+    pub timeout: Option<Duration>,
+    /// Restore the VM in this network namespace, like [`Vm::netns`]. The TAP interfaces
+    /// recorded in the snapshot must exist there, so clones in namespaces of their own can
+    /// each have interfaces with the template's names
+    pub netns: Option<NetNs>,
+    /// Gives the VM a vsock directory of its own, as `(snapshotted, dir)`: `snapshotted` is
+    /// the socket path recorded in the snapshot, its [`VsockConfig::uds_path`], and for this
+    /// VM only `dir` is bind-mounted over the directory holding it, in a mount namespace of
+    /// its own. The VM's socket, and the `<socket>_<port>` sockets connections the guest
+    /// opens go to, are then in `dir`, so clones restored at the same time, in this process
+    /// or another, don't collide. The snapshot files and disks must not be in the directory
+    /// that is mounted over. Needs `CAP_SYS_ADMIN`.
+    pub vsock_dir: Option<(PathBuf, PathBuf)>,
+}
+
+pub struct Vm {
+    /// Between 1 and firecracker's limit of 32
+    pub vcpu_count: u8,
+    pub mem_size_mib: usize,
+    pub kernel: KernelSource,
+    pub kernel_cmdline: String,
+    /// Size limit of the kernel command line in bytes, including the trailing NUL. Firecracker
+    /// appends its own device params, so leave some headroom - Defaults to 4096
+    pub cmdline_capacity: Option<usize>,
+    /// Params added to `kernel_cmdline`, ahead of any `--` that starts the init args. None
+    /// may contain a newline or NUL
+    pub extra_cmdline_args: Vec<String>,
+    pub vsock: Option<VsockConfig>,
+    pub initrd: Option<File>,
+    pub rootfs: Option<Disk>,
+    pub extra_disks: Vec<Disk>,
+    pub net_config: Option<NetConfig>,
+    /// Further network interfaces, after `net_config`
+    pub extra_nets: Vec<NetConfig>,
+    /// Run the VM in this network namespace, where its TAP interfaces must be
+    pub netns: Option<NetNs>,
+    /// TAP interfaces and host networks the VM owns, kept until it and its handles are gone
+    pub owned_nets: Vec<OwnedNet>,
+    /// Page size of the guest memory. Firecracker allocates that memory itself, as anonymous
+    /// memory, from hugepages if set; it cannot be backed by a file or shared
+    pub huge_pages: HugePages,
+    /// Skip the check that `mem_size_mib` fits in the host's available memory (or free
+    /// hugepages), for hosts that overcommit memory on purpose
+    pub allow_overcommit: bool,
+    pub entropy: Option<EntropyConfig>,
+    pub balloon: Option<BalloonConfig>,
+    pub cpu_template: Option<CpuTemplate>,
+    /// Expose hyperthread siblings to the guest. Only supported on x86_64, and requires
+    /// `vcpu_count` to be 1 or even.
+    pub smt: bool,
+    /// Host CPU to pin each vcpu thread to, indexed by vcpu. Must list one online CPU per vcpu
+    pub vcpu_affinity: Option<Vec<usize>>,
+    /// Track pages written by the guest from boot, required for diff snapshots
+    pub track_dirty_pages: bool,
+    /// Requires a network interface that sets [`NetConfig::allow_mmds`]; only those serve it
+    pub mmds: Option<MmdsConfig>,
+    /// Shows up in firecracker's logs and metrics, and as MMDS's instance id.
+    /// Defaults to a random UUID
+    pub instance_id: Option<String>,
+    /// Shows up in firecracker's logs. Defaults to this crate's name
+    pub app_name: Option<String>,
+    /// Defaults to this crate's version
+    pub vmm_version: Option<String>,
+    /// Write a `<app_name> instance <instance_id>` line to the serial output before booting,
+    /// so captured consoles say which VM they came from
+    pub serial_banner: bool,
+    /// The serial output writer runs on the event loop thread, so it is subject to the
+    /// `vmm` filter too
+    pub seccomp: Seccomp,
+    /// Serial output that marks the guest as booted, see [`VmHandle::wait_ready`]
+    pub ready_marker: Option<String>,
+    /// How many of the last console lines to keep, see [`VmHandle::console_tail`]. 0 keeps
+    /// none
+    pub console_tail: usize,
+    /// Attach firecracker's boot timer device. A guest that writes 123 to it makes firecracker
+    /// log its own boot time (`Guest-boot-time = ...`) through the `log` crate, which
+    /// [`VmHandle::boot_time`] returns with the logger of [`init_logger`] installed.
+    /// [`VmHandle::ready_after`] measures up to the `ready_marker` from the host instead.
+    pub boot_timer: bool,
+    /// Stop the VM if the guest has not exited this long after starting; `wait` (and `make`)
+    /// then fail with `SpawnError::Timeout`
+    pub timeout: Option<Duration>,
+    /// Whether [`Vm::make`] boots the VM again when the guest exits. Every boot gets fresh
+    /// devices and the same serial output; `timeout` applies to each boot on its own.
+    pub reboot_policy: RebootPolicy,
 }
 
-#[derive(Clone)]
-pub struct NetConfig {
-    /// Name of an unused TAP interface on the host, must exist
-    pub tap_iface_name: String,
-    /// Mac address - Leave blank for a default
-    pub vm_mac: Option<[u8; 6]>,
-}
+impl Vm {
+    pub fn builder() -> VmBuilder {
+        VmBuilder::new()
+    }
+
+    /// Reads a VM from a firecracker `--config-file` JSON document, using firecracker's
+    /// field names. The `boot-source`, `drives`, `machine-config`, `network-interfaces`,
+    /// `vsock`, `entropy`, `balloon` and `mmds-config` sections are supported; any other
+    /// section is rejected. Drive and interface ids are replaced by this crate's own.
+    ///
+    /// Defaults are firecracker's rather than this crate's: drives without a `cache_type`
+    /// use `CacheType::Unsafe`, and MMDS without a `version` is V1.
+    pub fn from_config_file(path: impl AsRef<Path>) -> Result<Vm, SpawnError> {
+        config::load(path.as_ref())
+    }
+
+    /// Boots the VM and blocks until the guest exits, returning its exit code. The VM is
+    /// booted again as long as `reboot_policy` allows.
+    ///
+    /// `output` receives everything the guest writes to its serial console. The console is
+    /// output only: the vmm fork gives the serial device no input source, so nothing can be
+    /// typed into the guest's `ttyS0`; send data to the guest over vsock instead.
+    pub fn make(&self, output: Box<dyn SerialOut>) -> Result<FcExitCode, SpawnError> {
+        Ok(self.boot_until_exit(output)?.0)
+    }
+
+    /// Like [`Vm::make`], but also reports how long the VM ran. Fails with
+    /// `SpawnError::GuestPanicked` if the guest kernel panicked on its last boot; otherwise
+    /// check `exit_code` to tell a clean shutdown from a crash.
+    pub fn run(&self, output: Box<dyn SerialOut>) -> Result<RunOutcome, SpawnError> {
+        let started_at = Instant::now();
+        let (exit_code, panic) = self.boot_until_exit(output)?;
+        if let Some(PanicReport {
+            message,
+            backtrace_lines,
+        }) = panic
+        {
+            return Err(SpawnError::GuestPanicked {
+                message,
+                backtrace_lines,
+            });
+        }
+        Ok(RunOutcome {
+            exit_code,
+            run_duration: started_at.elapsed(),
+        })
+    }
+
+    /// Boots the VM as many times as `reboot_policy` allows, returning how the last boot
+    /// ended
+    fn boot_until_exit(
+        &self,
+        output: Box<dyn SerialOut>,
+    ) -> Result<(FcExitCode, Option<PanicReport>), SpawnError> {
+        let output = SharedSerialOut::new(output);
+        let mut restarts = 0;
+        loop {
+            let (exit_code, panic) = self.start(Box::new(output.clone()))?.wait_for_panic()?;
+            if !self.reboot_policy.restarts(exit_code, restarts) {
+                return Ok((exit_code, panic));
+            }
+            restarts += 1;
+            info!("guest exited, restarting it ({restarts} restarts)");
+        }
+    }
+
+    /// Boots the VM and returns a handle to it while it runs in the background.
+    pub fn start(&self, output: Box<dyn SerialOut>) -> Result<VmHandle, SpawnError> {
+        self.validate()?;
+        let instance_info = self.instance_info();
+        let cid = match &self.vsock {
+            Some(vc) => Some(CidLease::acquire(vc.guest_cid)?),
+            None => None,
+        };
+        let guest_cid = cid.as_ref().map_or(FIRST_GUEST_CID, CidLease::cid);
+        let vm_resources = self.resources(&instance_info.id, guest_cid)?;
+        info!(
+            "{}: booting with {} vcpus and {} MiB",
+            instance_info.id, self.vcpu_count, self.mem_size_mib
+        );
+        let mut output = output;
+        if self.serial_banner {
+            // Like the serial device itself, a failing sink does not stop the VM
+            let _ = writeln!(
+                output,
+                "{} instance {}",
+                instance_info.app_name, instance_info.id
+            );
+        }
+        let (output, ready): (Box<dyn SerialOut>, _) = match &self.ready_marker {
+            Some(marker) => {
+                let ready = Arc::new(Ready::default());
+                let watcher = ReadyWatcher::new(output, marker, ready.clone());
+                (Box::new(watcher), Some(ready))
+            }
+            None => (output, None),
+        };
+        let (output, tail): (Box<dyn SerialOut>, _) = match self.console_tail {
+            0 => (output, None),
+            lines => {
+                let tail = Arc::new(ConsoleTail::new(lines));
+                (Box::new(TailWatcher::new(output, tail.clone())), Some(tail))
+            }
+        };
+        let serial = Arc::new(SerialWaiters::default());
+        let output = Box::new(SerialMatcher::new(output, serial.clone()));
+        let seccomp_filters = self.seccomp.filters()?;
+        let timeout = self.timeout;
+        let vcpu_affinity = self.vcpu_affinity.clone();
+        let boot = move || {
+            VmHandle::boot(
+                instance_info,
+                vm_resources,
+                seccomp_filters,
+                timeout,
+                output,
+                ready,
+                vcpu_affinity,
+            )
+        };
+        // The VM's threads start in the namespace of the thread that boots it
+        let mut handle = match &self.netns {
+            Some(netns) => netns.run(boot).map_err(SpawnError::NetNs)??,
+            None => boot()?,
+        };
+        handle.cid = cid;
+        handle.console_tail = tail;
+        handle.serial = Some(serial);
+        handle.owned_nets = self.owned_nets.clone();
+        handle.vsock_path = self.vsock.as_ref().map(|vc| PathBuf::from(&vc.uds_path));
+        handle.writable_disks = self
+            .drives()
+            .into_iter()
+            .filter(|(_, disk, _)| !disk.read_only && disk.socket.is_none())
+            .map(|(_, disk, _)| disk.path.clone())
+            .collect();
+        Ok(handle)
+    }
+
+    /// Same as [`Vm::start`]
+    pub fn spawn(&self, output: Box<dyn SerialOut>) -> Result<VmHandle, SpawnError> {
+        self.start(output)
+    }
+
+    /// Boots the VM and resolves to its exit code, see [`VmHandle::wait_async`]. Like
+    /// [`Vm::make`], follows `reboot_policy`. Dropping the future stops the VM.
+    ///
+    /// This adapts the threaded API rather than running the VM on the async runtime: booting
+    /// runs on the runtime's blocking pool, and each VM keeps an OS thread for firecracker's
+    /// event loop, which wakes up every 100ms. It saves the caller a thread per VM, not the
+    /// crate.
+    #[cfg(feature = "tokio")]
+    pub async fn start_async(self, output: Box<dyn SerialOut>) -> Result<FcExitCode, SpawnError> {
+        let output = SharedSerialOut::new(output);
+        let mut vm = self;
+        let mut restarts = 0;
+        loop {
+            let output = Box::new(output.clone());
+            let (returned, handle) = tokio::task::spawn_blocking(move || {
+                let handle = vm.start(output);
+                (vm, handle)
+            })
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+            vm = returned;
+            let exit_code = handle?.wait_async().await?;
+            if !vm.reboot_policy.restarts(exit_code, restarts) {
+                return Ok(exit_code);
+            }
+            restarts += 1;
+            info!("guest exited, restarting it ({restarts} restarts)");
+        }
+    }
+
+    /// Boots the VM as a template for clones: once the guest has written its `ready_marker`,
+    /// within `ready_timeout`, a full snapshot is written to `template.mem` and
+    /// `template.state` in the existing directory `dir`, and the VM is stopped. Clones are
+    /// then restored from the snapshot with [`Template::spawn`], skipping the boot.
+    pub fn clone_for_fork(
+        &self,
+        output: Box<dyn SerialOut>,
+        dir: impl AsRef<Path>,
+        ready_timeout: Duration,
+    ) -> Result<Template, SpawnError> {
+        let handle = self.start(output)?;
+        handle.wait_ready(ready_timeout)?;
+        let mem_path = dir.as_ref().join("template.mem");
+        let state_path = dir.as_ref().join("template.state");
+        handle.snapshot(&mem_path, &state_path, SnapshotType::Full, false)?;
+        Ok(Template::new(mem_path, state_path))
+    }
+
+    /// Restores a VM from a snapshot taken with [`VmHandle::snapshot`] and resumes it.
+    ///
+    /// Network interfaces reopen the TAP devices recorded in the snapshot by name, so those
+    /// must exist on the host at restore time.
+    ///
+    /// `mem_path` is mapped privately, so it is never written to and several VMs can be
+    /// restored from the same file, sharing its pages until they write to them.
+    pub fn from_snapshot(
+        mem_path: impl AsRef<Path>,
+        state_path: impl AsRef<Path>,
+        config: RestoreConfig,
+    ) -> Result<VmHandle, SpawnError> {
+        kvm::check()?;
+        let params = LoadSnapshotParams {
+            snapshot_path: state_path.as_ref().to_path_buf(),
+            mem_backend: MemBackendConfig {
+                backend_path: mem_path.as_ref().to_path_buf(),
+                backend_type: MemBackendType::File,
+            },
+            enable_diff_snapshots: config.track_dirty_pages,
+            resume_vm: false,
+        };
+        let instance_info = instance_info(config.instance_id);
+        let seccomp_filters = config.seccomp.filters()?;
+        let disk_paths = config.disk_paths;
+        let timeout = config.timeout;
+        let vsock_dir = config.vsock_dir;
+        let mount = vsock_dir.clone();
+        let restore = move || {
+            if let Some((snapshotted, dir)) = mount {
+                let target = snapshotted.parent().unwrap_or(Path::new("/"));
+                mountns::bind_private(&dir, target)
+                    .map_err(|source| SpawnError::VsockDir { path: dir, source })?;
+            }
+            VmHandle::restore(instance_info, params, disk_paths, seccomp_filters, timeout)
+        };
+        let mut handle = match &config.netns {
+            Some(netns) => netns.run(restore).map_err(SpawnError::NetNs)??,
+            // The mount namespace is per thread too, and must not be the caller's
+            None if vsock_dir.is_some() => thread::scope(|scope| {
+                thread::Builder::new()
+                    .spawn_scoped(scope, restore)
+                    .map_err(SpawnError::Thread)?
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })?,
+            None => restore()?,
+        };
+        if let Some((snapshotted, dir)) = vsock_dir {
+            handle.vsock_path = snapshotted.file_name().map(|name| dir.join(name));
+        }
+        Ok(handle)
+    }
+
+    /// Checks that the configuration is bootable without building any VM resources, that the
+    /// host has the memory for it, and that `/dev/kvm` is usable by this process.
+    pub fn validate(&self) -> Result<(), SpawnError> {
+        self.validate_config()?;
+        if !self.allow_overcommit {
+            if let Some(available) = meminfo::available_mib(self.huge_pages) {
+                if self.mem_size_mib > available {
+                    return Err(SpawnError::InsufficientMemory {
+                        requested: self.mem_size_mib,
+                        available,
+                    });
+                }
+            }
+        }
+        kvm::check()?;
+        Ok(())
+    }
+
+    /// The checks of [`Vm::validate`] that don't depend on the host's free memory or on KVM,
+    /// run by [`VmBuilder::build`]
+    pub(crate) fn validate_config(&self) -> Result<(), SpawnError> {
+        if self
+            .mmds
+            .as_ref()
+            .and_then(|mc| mc.data.as_ref())
+            .is_some_and(|data| !data.is_object())
+        {
+            return Err(SpawnError::MmdsNotObject {
+                key: "/".to_string(),
+            });
+        }
+        if self.mmds.is_some() && self.mmds_iface_ids().is_empty() {
+            return Err(SpawnError::MmdsNotAllowed);
+        }
+        if let Some(arg) = self
+            .extra_cmdline_args
+            .iter()
+            .find(|arg| arg.contains(['\n', '\0']))
+        {
+            return Err(SpawnError::InvalidCmdlineArg { arg: arg.clone() });
+        }
+        if let (Some(flag), Some(attached)) = (self.cmdline_root_flag(), self.root_flag()) {
+            if flag != attached {
+                return Err(SpawnError::RootFlagConflict {
+                    flag: flag.to_string(),
+                });
+            }
+        }
+        let cmdline = self.cmdline();
+        let capacity = self.cmdline_capacity();
+        if cmdline.len() >= capacity {
+            return Err(SpawnError::CmdlineTooLong {
+                len: cmdline.len(),
+                capacity,
+            });
+        }
+        if self.vcpu_count == 0 || self.vcpu_count > MAX_SUPPORTED_VCPUS {
+            return Err(SpawnError::InvalidVcpuCount {
+                requested: self.vcpu_count,
+                max: MAX_SUPPORTED_VCPUS,
+            });
+        }
+        if self.smt {
+            if cfg!(not(target_arch = "x86_64")) {
+                return Err(SpawnError::SmtNotSupported);
+            }
+            if self.vcpu_count > 1 && self.vcpu_count % 2 != 0 {
+                return Err(SpawnError::OddVcpuCountWithSmt {
+                    requested: self.vcpu_count,
+                });
+            }
+        }
+        if let Some(affinity) = &self.vcpu_affinity {
+            if affinity.len() != usize::from(self.vcpu_count) {
+                return Err(SpawnError::VcpuAffinityLength {
+                    len: affinity.len(),
+                    vcpu_count: self.vcpu_count,
+                });
+            }
+            let online = affinity::online_cpus().map_err(SpawnError::VcpuAffinity)?;
+            if let Some(&cpu) = affinity.iter().find(|cpu| !online.contains(cpu)) {
+                return Err(SpawnError::CpuOffline { cpu });
+            }
+        }
+        if self.mem_size_mib == 0 {
+            return Err(SpawnError::ZeroMemSize);
+        }
+        self.huge_pages.to_vmm()?;
+        if let Some(page_size_mib) = self.huge_pages.page_size_mib() {
+            if self.mem_size_mib % page_size_mib != 0 {
+                return Err(SpawnError::InvalidMemSize {
+                    mem_size_mib: self.mem_size_mib,
+                    page_size_mib,
+                });
+            }
+        }
+
+        let kernel_meta = self
+            .kernel
+            .open()?
+            .metadata()
+            .map_err(|source| SpawnError::Kernel {
+                path: self.kernel.path(),
+                source,
+            })?;
+        if !kernel_meta.is_file() {
+            return Err(SpawnError::Kernel {
+                path: self.kernel.path(),
+                source: io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"),
+            });
+        }
+
+        let root_disks = self.drives().iter().filter(|(_, _, root)| *root).count();
+        if root_disks > 1 {
+            return Err(SpawnError::MultipleRootDisks);
+        }
+        if root_disks == 0 && self.initrd.is_none() && !cmdline_sets_root(&cmdline) {
+            return Err(SpawnError::NoBootDevice);
+        }
+        for (id, disk, _) in self.drives() {
+            if disk.socket.is_some() != disk.path.as_os_str().is_empty() {
+                return Err(SpawnError::DiskBackend { id });
+            }
+            let path = disk.socket.as_ref().unwrap_or(&disk.path);
+            if !path.exists() {
+                return Err(SpawnError::DiskNotFound {
+                    id,
+                    path: path.clone(),
+                });
+            }
+        }
+
+        for (_, nc) in self.nets() {
+            if nc.tap_iface_name.is_empty() {
+                return Err(SpawnError::EmptyTapName);
+            }
+            // /sys/class/net shows the host's interfaces, not the namespace's; firecracker
+            // reports a missing TAP when it attaches instead
+            if self.netns.is_none() {
+                tap::check(&nc.tap_iface_name)?;
+            }
+        }
+        if let Some(cid) = self.vsock.as_ref().and_then(|vc| vc.guest_cid) {
+            // u32::MAX is VMADDR_CID_ANY
+            if cid < FIRST_GUEST_CID || cid == u32::MAX {
+                return Err(SpawnError::ReservedCid { cid });
+            }
+        }
+        Ok(())
+    }
+
+    /// Every configured disk with its drive id and whether it is the root device.
+    fn drives(&self) -> Vec<(String, &Disk, bool)> {
+        let rootfs = self.rootfs.iter().map(|d| ("block0".to_string(), d, true));
+        let extra = self
+            .extra_disks
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (format!("block{}", i + 1), d, d.is_root));
+        rootfs.chain(extra).collect()
+    }
+
+    /// Every configured network interface with its interface id, `net<index>`.
+    fn nets(&self) -> Vec<(String, &NetConfig)> {
+        self.net_config
+            .iter()
+            .chain(&self.extra_nets)
+            .enumerate()
+            .map(|(i, nc)| (format!("net{i}"), nc))
+            .collect()
+    }
+
+    /// Ids of the interfaces MMDS is served on
+    fn mmds_iface_ids(&self) -> Vec<String> {
+        self.nets()
+            .into_iter()
+            .filter(|(_, nc)| nc.allow_mmds)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// `ro` or `rw` matching how the root disk is attached, if it is a file
+    fn root_flag(&self) -> Option<&'static str> {
+        self.drives()
+            .into_iter()
+            .find(|(_, disk, is_root)| *is_root && disk.socket.is_none())
+            .map(|(_, disk, _)| if disk.read_only { "ro" } else { "rw" })
+    }
+
+    /// The `ro` or `rw` the user put on the cmdline; the kernel goes by the last one
+    fn cmdline_root_flag(&self) -> Option<&str> {
+        let (params, _) = cmdline::boot_params(&self.kernel_cmdline);
+        params
+            .into_iter()
+            .rfind(|param| matches!(*param, "ro" | "rw"))
+    }
+
+    /// `kernel_cmdline` with the `extra_cmdline_args` inserted before `--`, if any, followed
+    /// by `ro` or `rw` matching the root disk so the guest mounts it the way it is attached,
+    /// unless the cmdline already has it. The user's cmdline is otherwise kept as written.
+    fn cmdline(&self) -> String {
+        let mut inserted: Vec<&str> = self.extra_cmdline_args.iter().map(String::as_str).collect();
+        if self.cmdline_root_flag().is_none() {
+            inserted.extend(self.root_flag());
+        }
+        if inserted.is_empty() {
+            return self.kernel_cmdline.clone();
+        }
+        let (_, init_args) = cmdline::boot_params(&self.kernel_cmdline);
+        let (boot, init) = self.kernel_cmdline.split_at(init_args);
+        let mut cmdline = boot.to_string();
+        if !cmdline.is_empty() && !cmdline.ends_with(char::is_whitespace) {
+            cmdline.push(' ');
+        }
+        cmdline.push_str(&inserted.join(" "));
+        if !init.is_empty() {
+            cmdline.push(' ');
+            cmdline.push_str(init);
+        }
+        cmdline
+    }
+
+    fn cmdline_capacity(&self) -> usize {
+        self.cmdline_capacity.unwrap_or(DEFAULT_CMDLINE_CAPACITY)
+    }
+
+    fn instance_info(&self) -> InstanceInfo {
+        let mut info = instance_info(self.instance_id.clone());
+        if let Some(app_name) = &self.app_name {
+            info.app_name = app_name.clone();
+        }
+        if let Some(vmm_version) = &self.vmm_version {
+            info.vmm_version = vmm_version.clone();
+        }
+        info
+    }
+
+    /// `guest_cid` is given to the vsock device, if there is one.
+    fn resources(&self, instance_id: &str, guest_cid: u32) -> Result<VmResources, SpawnError> {
+        let vm_config = VmConfig {
+            vcpu_count: self.vcpu_count,
+            mem_size_mib: self.mem_size_mib,
+            smt: self.smt,
+            cpu_template: self
+                .cpu_template
+                .as_ref()
+                .map(CpuTemplate::to_vmm)
+                .transpose()?,
+            track_dirty_pages: self.track_dirty_pages,
+            huge_pages: self.huge_pages.to_vmm()?,
+        };
+        let initrd = match &self.initrd {
+            None => None,
+            Some(f) => Some(f.try_clone().map_err(SpawnError::Initrd)?),
+        };
+        let boot_source = BootSource {
+            config: BootSourceConfig::default(),
+            builder: Some(BootConfig {
+                cmdline: linux_loader::cmdline::Cmdline::try_from(
+                    &self.cmdline(),
+                    self.cmdline_capacity(),
+                )?,
+                kernel_file: self.kernel.open()?,
+                initrd_file: initrd,
+            }),
+        };
+
+        let mut net_builder = NetBuilder::new();
+        for (index, (iface_id, nc)) in (0..).zip(self.nets()) {
+            let mac = nc
+                .vm_mac
+                .unwrap_or_else(|| NetConfig::default_mac(instance_id, index));
+            debug!(
+                "{instance_id}: attaching {iface_id} to TAP {}",
+                nc.tap_iface_name
+            );
+            net_builder
+                .build(NetworkInterfaceConfig {
+                    iface_id: iface_id.clone(),
+                    host_dev_name: nc.tap_iface_name.clone(),
+                    guest_mac: Some(MacAddr::from_bytes_unchecked(&mac)),
+                    rx_rate_limiter: nc.rx_rate_limiter.map(Into::into),
+                    tx_rate_limiter: nc.tx_rate_limiter.map(Into::into),
+                })
+                .map_err(|source| SpawnError::Net { iface_id, source })?;
+        }
+
+        let mut block = BlockBuilder::new();
+
+        for (drive_id, disk, is_root_device) in self.drives() {
+            debug!(
+                "{instance_id}: attaching {drive_id} backed by {}",
+                disk.socket.as_ref().unwrap_or(&disk.path).display()
+            );
+            let (path_on_host, is_read_only) = match disk.socket {
+                Some(_) => (None, None),
+                None => (
+                    Some(disk.path.as_path().display().to_string()),
+                    Some(disk.read_only),
+                ),
+            };
+            block
+                .insert(BlockDeviceConfig {
+                    drive_id: drive_id.clone(),
+                    partuuid: disk.partuuid.clone(),
+                    is_root_device,
+                    cache_type: disk.cache_type(),
+
+                    is_read_only,
+                    path_on_host,
+                    rate_limiter: disk.rate_limiter.map(Into::into),
+                    file_engine_type: disk.file_engine_type,
+
+                    socket: disk.socket.as_ref().map(|s| s.display().to_string()),
+                })
+                .map_err(|source| SpawnError::Disk {
+                    id: drive_id,
+                    source,
+                })?;
+        }
+
+        let mut vsock = VsockBuilder::new();
+        if let Some(ref vc) = self.vsock {
+            let cfg = VsockDeviceConfig {
+                vsock_id: None,
+                guest_cid,
+                uds_path: vc.uds_path.clone(),
+            };
+            vsock.insert(cfg)?;
+        }
+
+        let mut entropy = EntropyDeviceBuilder::new();
+        if let Some(ec) = &self.entropy {
+            entropy.insert(EntropyDeviceConfig {
+                rate_limiter: ec.rate_limiter.map(Into::into),
+            })?;
+        }
+
+        let mut balloon = BalloonBuilder::new();
+        if let Some(bc) = &self.balloon {
+            balloon.set(BalloonDeviceConfig {
+                amount_mib: bc.amount_mib,
+                deflate_on_oom: bc.deflate_on_oom,
+                stats_polling_interval_s: bc.stats_polling_interval_s,
+            })?;
+        }
+
+        let mut vm_resources = VmResources {
+            vm_config,
+            boot_source,
+            net_builder,
+            block,
+            boot_timer: self.boot_timer,
+            vsock,
+            entropy,
+            balloon,
+            ..Default::default()
+        };
+
+        if let Some(mc) = &self.mmds {
+            vm_resources.set_mmds_config(
+                VmmMmdsConfig {
+                    version: mc.version,
+                    network_interfaces: self.mmds_iface_ids(),
+                    ipv4_address: mc.ipv4_address,
+                },
+                instance_id,
+            )?;
+            if let Some(data) = &mc.data {
+                vm_resources
+                    .locked_mmds_or_default()
+                    .put_data(data.clone())?;
+            }
+        }
+
+        Ok(vm_resources)
+    }
+}
+
+fn instance_info(id: Option<String>) -> InstanceInfo {
+    InstanceInfo {
+        id: id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+        state: VmState::NotStarted,
+        vmm_version: env!("CARGO_PKG_VERSION").to_string(),
+        app_name: env!("CARGO_PKG_NAME").to_string(),
+    }
+}
+
+/// Whether `cmdline` names the root device or init itself (e.g. `root=PARTUUID=..` on an
+/// extra disk), in which case neither a rootfs nor an initrd is needed. Init's own arguments
+/// after `--` are ignored.
+pub(crate) fn cmdline_sets_root(cmdline: &str) -> bool {
+    let (params, _) = cmdline::boot_params(cmdline);
+    params
+        .into_iter()
+        .any(|param| param.starts_with("root=") || param.starts_with("init="))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affinity;
+    use crate::boottime::{record_boot_time, BootTime};
+    use crate::serial::{
+        ConsoleTail, GuestPanic, PanicWatcher, SerialMatcher, SerialWaiters, TailWatcher,
+    };
+    use crate::{
+        init_metrics, memfd, regex::Regex, BalloonConfig, CacheType, CmdlineBuilder, CpuTemplate,
+        Disk, EntropyConfig, ExitReason, FcExitCode, HostNetwork, HugePages, InitrdBuilder,
+        KernelSource, LineSerialOut, MmdsConfig, NetConfig, NetNs, PrefixSerialOut, PtySerialOut,
+        RateLimit, RebootPolicy, RestoreConfig, Seccomp, SnapshotType, SpawnError, Tap,
+        TeeSerialOut, TokenBucket, Vm, VmBuilder, VmState, VsockConfig, OVERLAYROOT_PARAM,
+    };
+    use cpio::NewcReader;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::net::Ipv4Addr;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixListener;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use std::{io, thread};
+    use test_binary::TestBinary;
+    #[test]
+    fn it_works_net() {
+        let kernel = KernelSource::Path(PathBuf::from("vmlinux"));
+        let v = Vm {
+            vcpu_count: 1,
+            mem_size_mib: 32,
+            kernel,
+            kernel_cmdline: "quiet panic=-1 reboot=t init=/goinit".to_string(),
+            cmdline_capacity: None,
+            extra_cmdline_args: vec![],
+            rootfs: Some(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: false,
+                ..Default::default()
+            }),
+            initrd: None,
+            extra_disks: vec![],
+            net_config: Some(NetConfig {
+                tap_iface_name: "mytap0".to_string(),
+                vm_mac: None,
+                ..Default::default()
+            }),
+            extra_nets: vec![],
+            netns: None,
+            owned_nets: Vec::new(),
+            huge_pages: HugePages::None,
+            allow_overcommit: false,
+            entropy: None,
+            balloon: None,
+            cpu_template: None,
+            smt: false,
+            vcpu_affinity: None,
+            track_dirty_pages: false,
+            mmds: None,
+            instance_id: None,
+            app_name: None,
+            vmm_version: None,
+            serial_banner: false,
+            seccomp: Seccomp::Default,
+            ready_marker: None,
+            console_tail: 0,
+            boot_timer: false,
+            timeout: None,
+            reboot_policy: RebootPolicy::Exit,
+            vsock: None,
+        };
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_parses_macs() {
+        assert_eq!(
+            NetConfig::parse_mac("52:54:00:12:34:ab").unwrap(),
+            [0x52, 0x54, 0x00, 0x12, 0x34, 0xab]
+        );
+        for mac in [
+            "",
+            "52:54:00:12:34",
+            "52:54:00:12:34:56:78",
+            "52-54-00-12-34-56",
+            "zz:54:00:12:34:56",
+        ] {
+            assert!(matches!(
+                NetConfig::parse_mac(mac),
+                Err(SpawnError::InvalidMac { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn it_derives_unique_default_macs() {
+        let guest_mac = |id: &str, tap: &str| {
+            let v = Vm::builder()
+                .kernel(PathBuf::from("vmlinux"))
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .net(NetConfig {
+                    tap_iface_name: tap.to_string(),
+                    ..Default::default()
+                })
+                .build()
+                .unwrap();
+            let configs = v.resources(id, 3).unwrap().net_builder.configs();
+            configs[0].guest_mac.as_ref().unwrap().get_bytes().to_vec()
+        };
+        let a = guest_mac("vm-a", "mytap0");
+        let b = guest_mac("vm-b", "mytap1");
+        assert_ne!(a, b);
+        assert_eq!(a, NetConfig::default_mac("vm-a", 0));
+        assert_eq!(a[0], 0x02);
+        assert_eq!(b[0], 0x02);
+        assert_ne!(
+            NetConfig::default_mac("vm-a", 0),
+            NetConfig::default_mac("vm-a", 1)
+        );
+    }
+
+    #[test]
+    fn it_limits_net_tx() {
+        let ten_mib_per_sec = RateLimit {
+            bandwidth: Some(TokenBucket {
+                size: 10 * 1024 * 1024,
+                one_time_burst: None,
+                refill_time_ms: 1000,
+            }),
+            ops: None,
+        };
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .net(NetConfig {
+                tap_iface_name: "mytap0".to_string(),
+                tx_rate_limiter: Some(ten_mib_per_sec),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let configs = v.resources("test", 3).unwrap().net_builder.configs();
+        let tx = configs[0].tx_rate_limiter.as_ref().unwrap();
+        let bandwidth = tx.bandwidth.as_ref().unwrap();
+        assert_eq!(bandwidth.size, 10 * 1024 * 1024);
+        assert_eq!(bandwidth.refill_time, 1000);
+        assert!(configs[0].rx_rate_limiter.is_none());
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_names_the_failing_net() {
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .net(NetConfig {
+                tap_iface_name: "mytap0".to_string(),
+                ..Default::default()
+            })
+            .add_net(NetConfig {
+                tap_iface_name: "mytap0".to_string(),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let err = v.resources("test", 3).err().unwrap();
+        assert!(matches!(&err, SpawnError::Net { iface_id, .. } if iface_id == "net1"));
+        assert!(err.to_string().contains("net1"));
+    }
+
+    #[test]
+    fn it_seeds_mmds_user_data() {
+        let user_data_path = "/tmp/fcs-user-data";
+        fs::write(user_data_path, "#cloud-config\nhostname: test\n").unwrap();
+
+        let mut mmds = MmdsConfig::default();
+        mmds.set_json(serde_json::json!({"latest": {"meta-data": {"instance-id": "i-test"}}}))
+            .unwrap();
+        mmds.set_user_data(user_data_path, false).unwrap();
+        assert_eq!(
+            mmds.data,
+            Some(serde_json::json!({"latest": {
+                "meta-data": {"instance-id": "i-test"},
+                "user-data": "#cloud-config\nhostname: test\n",
+            }}))
+        );
+        mmds.set_user_data(user_data_path, true).unwrap();
+        assert_eq!(
+            mmds.data.as_ref().unwrap()["latest"]["user-data"],
+            "I2Nsb3VkLWNvbmZpZwpob3N0bmFtZTogdGVzdAo="
+        );
+        fs::remove_file(user_data_path).unwrap();
+
+        assert!(matches!(
+            mmds.set_json(serde_json::json!(["not", "an", "object"])),
+            Err(SpawnError::MmdsNotObject { key }) if key == "/"
+        ));
+        assert!(matches!(
+            Vm::builder()
+                .kernel(PathBuf::from("vmlinux"))
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .net(NetConfig {
+                    tap_iface_name: "mytap0".to_string(),
+                    ..Default::default()
+                })
+                .mmds(MmdsConfig {
+                    data: Some(serde_json::json!("user-data")),
+                    ..Default::default()
+                })
+                .build(),
+            Err(SpawnError::MmdsNotObject { .. })
+        ));
+    }
+
+    #[test]
+    fn it_scopes_mmds_to_allowed_nets() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .net(NetConfig {
+                tap_iface_name: "mytap0".to_string(),
+                ..Default::default()
+            })
+            .add_net(NetConfig {
+                tap_iface_name: "mytap1".to_string(),
+                allow_mmds: true,
+                ..Default::default()
+            })
+            .mmds(MmdsConfig::default())
+            .build()
+            .unwrap();
+        assert_eq!(v.mmds_iface_ids(), vec!["net1"]);
+        let net_ids: Vec<String> = v
+            .resources("test", 3)
+            .unwrap()
+            .net_builder
+            .configs()
+            .into_iter()
+            .map(|cfg| cfg.iface_id)
+            .collect();
+        assert_eq!(net_ids, vec!["net0", "net1"]);
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+
+        // Without any opt-in, no interface serves MMDS
+        assert!(matches!(
+            Vm::builder()
+                .kernel(PathBuf::from("vmlinux"))
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .net(NetConfig {
+                    tap_iface_name: "mytap0".to_string(),
+                    ..Default::default()
+                })
+                .add_net(NetConfig {
+                    tap_iface_name: "mytap1".to_string(),
+                    ..Default::default()
+                })
+                .mmds(MmdsConfig::default())
+                .build(),
+            Err(SpawnError::MmdsNotAllowed)
+        ));
+    }
+
+    #[test]
+    fn it_works_mmds() {
+        let data = serde_json::json!({"latest": {"meta-data": {"instance-id": "i-test"}}});
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .net(NetConfig {
+                tap_iface_name: "mytap0".to_string(),
+                allow_mmds: true,
+                ..Default::default()
+            })
+            .mmds(MmdsConfig {
+                data: Some(data.clone()),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let mut resources = v.resources("test", 3).unwrap();
+        assert_eq!(resources.locked_mmds_or_default().data_store_value(), data);
+        drop(resources);
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_serves_mmds_only_on_allowed_nets() {
+        let cpio_path = "my_mmds_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+
+        let vsock_path = "/tmp/test-mmds.v.sock";
+        let _ = fs::remove_file(vsock_path);
+        let vsock = VsockConfig::new(vsock_path);
+        let report = vsock.listen(1234).unwrap();
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- mmds")
+            .initrd(File::open(cpio_path).unwrap())
+            .net(NetConfig {
+                tap_iface_name: "mytap0".to_string(),
+                ..Default::default()
+            })
+            .add_net(NetConfig {
+                tap_iface_name: "mytap1".to_string(),
+                allow_mmds: true,
+                ..Default::default()
+            })
+            .mmds(MmdsConfig {
+                data: Some(serde_json::json!({"latest": {}})),
+                ..Default::default()
+            })
+            .vsock(vsock)
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+
+        let (mut stream, _) = report.accept().unwrap();
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).unwrap();
+        let lines: Vec<&str> = buf.lines().collect();
+        assert!(lines[0].starts_with("eth0 unreachable"), "{buf}");
+        assert_eq!(lines[1], "eth1 reached", "{buf}");
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_works_disk() {
+        let kernel = KernelSource::Path(PathBuf::from("vmlinux"));
+        let v = Vm {
+            vcpu_count: 1,
+            mem_size_mib: 32,
+            kernel,
+            kernel_cmdline: "quiet panic=-1 reboot=t init=/goinit".to_string(),
+            cmdline_capacity: None,
+            extra_cmdline_args: vec![],
+            rootfs: Some(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: false,
+                ..Default::default()
+            }),
+            initrd: None,
+            extra_disks: vec![Disk {
+                path: PathBuf::from("/home/david/git/lk/disk.tar.gz"),
+                read_only: true,
+                ..Default::default()
+            }],
+            net_config: None,
+            extra_nets: vec![],
+            netns: None,
+            owned_nets: Vec::new(),
+            huge_pages: HugePages::None,
+            allow_overcommit: false,
+            entropy: None,
+            balloon: None,
+            cpu_template: None,
+            smt: false,
+            vcpu_affinity: None,
+            track_dirty_pages: false,
+            mmds: None,
+            instance_id: None,
+            app_name: None,
+            vmm_version: None,
+            serial_banner: false,
+            seccomp: Seccomp::Default,
+            ready_marker: None,
+            console_tail: 0,
+            boot_timer: false,
+            timeout: None,
+            reboot_policy: RebootPolicy::Exit,
+            vsock: None,
+        };
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_sets_up_overlay_rootfs() {
+        let upper = "/tmp/fcs-upper.img";
+        File::create(upper)
+            .unwrap()
+            .set_len(16 * 1024 * 1024)
+            .unwrap();
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline(format!(
+                "quiet panic=-1 reboot=t init=/goinit {OVERLAYROOT_PARAM}"
+            ))
+            .add_disk(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .overlay_rootfs(
+                Disk {
+                    path: PathBuf::from("rootfs.ext4"),
+                    ..Default::default()
+                },
+                Disk {
+                    path: PathBuf::from(upper),
+                    ..Default::default()
+                },
+            )
+            .build()
+            .unwrap();
+        let configs = v.resources("test", 3).unwrap().block.configs();
+        assert!(configs[0].is_root_device);
+        assert_eq!(configs[0].is_read_only, Some(true));
+        assert_eq!(configs[1].path_on_host.as_deref(), Some(upper));
+        assert_eq!(configs[1].is_read_only, Some(false));
+        assert_eq!(configs.len(), 3);
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_assigns_unique_drive_ids() {
+        let extra_disk = Disk {
+            path: PathBuf::from("/home/david/git/lk/disk.tar.gz"),
+            read_only: true,
+            ..Default::default()
+        };
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .add_disk(extra_disk.clone())
+            .add_disk(extra_disk)
+            .build()
+            .unwrap();
+        let drive_ids: Vec<String> = v
+            .resources("test", 3)
+            .unwrap()
+            .block
+            .configs()
+            .into_iter()
+            .map(|cfg| cfg.drive_id)
+            .collect();
+        assert_eq!(drive_ids, vec!["block0", "block1", "block2"]);
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_boots_from_an_extra_root_disk() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .add_disk(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .add_disk(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                is_root: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let configs = v.resources("test", 3).unwrap().block.configs();
+        let root: Vec<&str> = configs
+            .iter()
+            .filter(|cfg| cfg.is_root_device)
+            .map(|cfg| cfg.drive_id.as_str())
+            .collect();
+        assert_eq!(root, vec!["block2"]);
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+
+        assert!(matches!(
+            Vm::builder()
+                .kernel(PathBuf::from("vmlinux"))
+                .rootfs(Disk {
+                    path: PathBuf::from("rootfs.ext4"),
+                    ..Default::default()
+                })
+                .add_disk(Disk {
+                    path: PathBuf::from("rootfs.ext4"),
+                    is_root: true,
+                    ..Default::default()
+                })
+                .build(),
+            Err(SpawnError::MultipleRootDisks)
+        ));
+    }
+
+    #[test]
+    fn it_sets_partuuid() {
+        let partuuid = "6c6d4c4e-01".to_string();
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs-partitioned.img"),
+                partuuid: Some(partuuid.clone()),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let configs = v.resources("test", 3).unwrap().block.configs();
+        assert_eq!(configs[0].partuuid, Some(partuuid));
+        assert!(configs[0].is_root_device);
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_configures_vhost_user_disks() {
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .add_disk(Disk {
+                socket: Some(PathBuf::from("/tmp/vhost-user-blk.sock")),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let configs = v.resources("test", 3).unwrap().block.configs();
+        assert_eq!(
+            configs[0].socket.as_deref(),
+            Some("/tmp/vhost-user-blk.sock")
+        );
+        assert_eq!(configs[0].path_on_host, None);
+        assert_eq!(configs[0].is_read_only, None);
+    }
+
+    #[test]
+    fn it_picks_cache_types() {
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .add_disk(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .add_disk(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                cache_type: Some(CacheType::Unsafe),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let cache_types: Vec<CacheType> = v
+            .resources("test", 3)
+            .unwrap()
+            .block
+            .configs()
+            .into_iter()
+            .map(|cfg| cfg.cache_type)
+            .collect();
+        assert_eq!(
+            cache_types,
+            vec![CacheType::Writeback, CacheType::Unsafe, CacheType::Unsafe]
+        );
+    }
+
+    #[test]
+    fn it_limits_disk_ops() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .add_disk(Disk {
+                path: PathBuf::from("/home/david/git/lk/disk.tar.gz"),
+                read_only: true,
+                rate_limiter: Some(RateLimit {
+                    bandwidth: None,
+                    ops: Some(TokenBucket {
+                        size: 100,
+                        one_time_burst: None,
+                        refill_time_ms: 1000,
+                    }),
+                }),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let configs = v.resources("test", 3).unwrap().block.configs();
+        let extra = configs.iter().find(|cfg| cfg.drive_id == "block1").unwrap();
+        let ops = extra.rate_limiter.as_ref().unwrap().ops.as_ref().unwrap();
+        assert_eq!(ops.size, 100);
+        assert_eq!(ops.refill_time, 1000);
+        let rootfs = configs.iter().find(|cfg| cfg.drive_id == "block0").unwrap();
+        assert!(rootfs.rate_limiter.is_none());
+    }
+
+    #[test]
+    fn it_works_initrd() {
+        let kernel = KernelSource::Path(PathBuf::from("vmlinux"));
+        let v = Vm {
+            vcpu_count: 1,
+            mem_size_mib: 32,
+            kernel,
+            kernel_cmdline: "panic=-1 reboot=t init=/init".to_string(),
+            cmdline_capacity: None,
+            extra_cmdline_args: vec![],
+            rootfs: None,
+            initrd: Some(File::open("bootstrap-initrd.cpio.gz").unwrap()),
+            extra_disks: vec![],
+            net_config: None,
+            extra_nets: vec![],
+            netns: None,
+            owned_nets: Vec::new(),
+            huge_pages: HugePages::None,
+            allow_overcommit: false,
+            entropy: None,
+            balloon: None,
+            cpu_template: None,
+            smt: false,
+            vcpu_affinity: None,
+            track_dirty_pages: false,
+            mmds: None,
+            instance_id: None,
+            app_name: None,
+            vmm_version: None,
+            serial_banner: false,
+            seccomp: Seccomp::Default,
+            ready_marker: None,
+            console_tail: 0,
+            boot_timer: false,
+            timeout: None,
+            reboot_policy: RebootPolicy::Exit,
+            vsock: None,
+        };
+        assert_eq!(v.make(Box::new(io::stdout())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_reports_run_outcome() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        let outcome = v.run(Box::new(io::sink())).unwrap();
+        assert_eq!(outcome.exit_code, FcExitCode::Ok);
+        assert!(outcome.run_duration > Duration::ZERO);
+    }
+
+    #[test]
+    fn it_works_entropy() {
+        let cpio_path = "my_hwrng_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+
+        // What the guest reports with and without the entropy device
+        let report = |entropy: Option<EntropyConfig>| {
+            let vsock_path = "/tmp/test-hwrng.v.sock";
+            let _ = fs::remove_file(vsock_path);
+            let vsock = VsockConfig::new(vsock_path);
+            let listener = vsock.listen(1234).unwrap();
+            let mut builder = Vm::builder()
+                .mem_mib(256)
+                .kernel(PathBuf::from("vmlinux"))
+                .cmdline("quiet panic=-1 reboot=t init=/init -- hwrng")
+                .initrd(File::open(cpio_path).unwrap())
+                .vsock(vsock);
+            if let Some(entropy) = entropy {
+                builder = builder.entropy(entropy);
+            }
+            let v = builder.build().unwrap();
+            assert_eq!(
+                v.resources("test", 3).unwrap().entropy.get().is_some(),
+                v.entropy.is_some()
+            );
+            let handle = v.start(Box::new(io::sink())).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut report = String::new();
+            stream.read_to_string(&mut report).unwrap();
+            assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+            report
+        };
+        assert_eq!(
+            report(Some(EntropyConfig::default())),
+            "hwrng virtio_rng.0\n"
+        );
+        assert!(report(None).starts_with("no hwrng"));
+    }
+
+    #[test]
+    fn it_limits_entropy() {
+        let limit = RateLimit {
+            bandwidth: Some(TokenBucket {
+                size: 4096,
+                one_time_burst: Some(65536),
+                refill_time_ms: 100,
+            }),
+            ops: None,
+        };
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .entropy(EntropyConfig {
+                rate_limiter: Some(limit),
+            })
+            .build()
+            .unwrap();
+        let config = v.resources("test", 3).unwrap().entropy.config().unwrap();
+        let bandwidth = config.rate_limiter.unwrap().bandwidth.unwrap();
+        assert_eq!(bandwidth.size, 4096);
+        assert_eq!(bandwidth.one_time_burst, Some(65536));
+        assert_eq!(bandwidth.refill_time, 100);
+        assert!(config.rate_limiter.unwrap().ops.is_none());
+        assert_eq!(RateLimit::from(config.rate_limiter.unwrap()), limit);
+    }
+
+    #[test]
+    fn it_appends_extra_cmdline_args() {
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .cmdline(CmdlineBuilder::new().param("panic", "-1").build().unwrap())
+            .add_cmdline_arg("reboot=t")
+            .add_cmdline_arg("init=/init")
+            .build()
+            .unwrap();
+        assert_eq!(v.cmdline(), "panic=-1 reboot=t init=/init");
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+
+        // Init args stay last
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 init=/init -- --verbose")
+            .add_cmdline_arg("quiet")
+            .build()
+            .unwrap();
+        assert_eq!(v.cmdline(), "panic=-1 init=/init quiet -- --verbose");
+
+        assert!(matches!(
+            Vm::builder()
+                .kernel(PathBuf::from("vmlinux"))
+                .cmdline("init=/init")
+                .add_cmdline_arg("quiet\nloglevel=7")
+                .build(),
+            Err(SpawnError::InvalidCmdlineArg { arg }) if arg == "quiet\nloglevel=7"
+        ));
+
+        assert!(matches!(
+            Vm::builder()
+                .kernel(PathBuf::from("vmlinux"))
+                .cmdline("init=/init")
+                .cmdline_capacity(16)
+                .add_cmdline_arg("loglevel=7")
+                .build(),
+            Err(SpawnError::CmdlineTooLong {
+                len: 21,
+                capacity: 16
+            })
+        ));
+    }
+
+    #[test]
+    fn it_mounts_the_rootfs_as_attached() {
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(v.cmdline(), "panic=-1 init=/goinit ro");
+
+        // Quoted values and spacing are kept, and the flag goes before init's args
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1  foo=\"a -- b\" init=/goinit -- rw")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .add_cmdline_arg("quiet")
+            .build()
+            .unwrap();
+        assert_eq!(
+            v.cmdline(),
+            "panic=-1  foo=\"a -- b\" init=/goinit quiet rw -- rw"
+        );
+
+        // A matching flag is not repeated, a conflicting one is rejected
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 ro init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(v.cmdline(), "panic=-1 ro init=/goinit");
+        assert!(matches!(
+            Vm::builder()
+                .kernel(PathBuf::from("vmlinux"))
+                .cmdline("panic=-1 rw init=/goinit")
+                .rootfs(Disk {
+                    path: PathBuf::from("rootfs.ext4"),
+                    read_only: true,
+                    ..Default::default()
+                })
+                .build(),
+            Err(SpawnError::RootFlagConflict { flag }) if flag == "rw"
+        ));
+
+        // Without a root disk the cmdline is left alone
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1  ro")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(v.cmdline(), "panic=-1  ro");
+    }
+
+    #[test]
+    fn it_reports_kernel_panics() {
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            // Without an init the kernel falls back to mounting a root disk, and panics
+            .cmdline("panic=0 rdinit=/does-not-exist")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .timeout(Duration::from_secs(30))
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        assert_eq!(handle.wait_reason().unwrap(), ExitReason::KernelPanic);
+        match v.run(Box::new(io::sink())) {
+            Err(SpawnError::GuestPanicked {
+                message,
+                backtrace_lines,
+            }) => {
+                assert!(message.starts_with("VFS: Unable to mount root fs"));
+                assert!(backtrace_lines.iter().any(|l| l.contains("Call Trace:")));
+            }
+            other => panic!("expected a panic report, got {other:?}"),
+        }
+
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        assert_eq!(handle.wait_reason().unwrap(), ExitReason::Shutdown);
+    }
+
+    #[test]
+    fn it_updates_drives() {
+        let data_path = "/tmp/fcs-data.img";
+        File::create(data_path)
+            .unwrap()
+            .set_len(1024 * 1024)
+            .unwrap();
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .add_disk(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        handle.update_drive("block1", data_path).unwrap();
+        assert!(matches!(
+            handle.update_drive("block2", data_path),
+            Err(SpawnError::UnknownDrive { id }) if id == "block2"
+        ));
+        assert!(matches!(
+            handle.update_drive("block1", "does-not-exist.img"),
+            Err(SpawnError::DiskNotFound { .. })
+        ));
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+        fs::remove_file(data_path).unwrap();
+    }
+
+    #[test]
+    fn it_works_balloon() {
+        let v = Vm::builder()
+            .mem_mib(64)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .balloon(BalloonConfig {
+                amount_mib: 16,
+                deflate_on_oom: true,
+                stats_polling_interval_s: 1,
+            })
+            .build()
+            .unwrap();
+        assert!(v.resources("test", 3).unwrap().balloon.get().is_some());
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        handle.balloon_set_target(8).unwrap();
+        assert_eq!(handle.balloon_stats().unwrap().target_mib, 8);
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_rejects_unknown_cpu_templates() {
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .cpu_template(CpuTemplate::Static("NotATemplate".to_string()))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            v.resources("test", 3),
+            Err(SpawnError::UnknownCpuTemplate { name }) if name == "NotATemplate"
+        ));
+    }
+
+    #[test]
+    fn it_tracks_dirty_pages() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .track_dirty_pages(true)
+            .build()
+            .unwrap();
+        assert!(v.resources("test", 3).unwrap().vm_config.track_dirty_pages);
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_lists_dirty_pages() {
+        let cpio_path = "my_dirty_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- hang")
+            .initrd(File::open(cpio_path).unwrap())
+            .track_dirty_pages(true)
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        assert!(matches!(handle.dirty_pages(), Err(SpawnError::NotPaused)));
+        handle.pause().unwrap();
+        let dirty = handle.dirty_pages().unwrap();
+        assert!(!dirty.is_empty());
+        let mut page = vec![0; (dirty[0].end - dirty[0].start) as usize];
+        handle.read_memory(dirty[0].start, &mut page).unwrap();
+        // Nothing ran since the previous query
+        assert!(handle.dirty_pages().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_snapshots() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        assert!(matches!(
+            handle.snapshot("/tmp/fcs.mem", "/tmp/fcs.state", SnapshotType::Diff, true),
+            Err(SpawnError::DirtyPageTrackingDisabled)
+        ));
+        handle.pause().unwrap();
+        assert!(matches!(
+            handle.dirty_pages(),
+            Err(SpawnError::DirtyPageTrackingDisabled)
+        ));
+        handle
+            .snapshot("/tmp/fcs.mem", "/tmp/fcs.state", SnapshotType::Full, true)
+            .unwrap();
+        assert!(fs::metadata("/tmp/fcs.mem").unwrap().len() >= 32 * 1024 * 1024);
+        assert!(fs::metadata("/tmp/fcs.state").is_ok());
+    }
+
+    #[test]
+    fn it_takes_diff_snapshots() {
+        use std::os::unix::fs::MetadataExt;
+
+        let cpio_path = "my_diff_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- hang")
+            .initrd(File::open(cpio_path).unwrap())
+            .track_dirty_pages(true)
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        handle
+            .snapshot(
+                "/tmp/fcs-base.mem",
+                "/tmp/fcs-base.state",
+                SnapshotType::Full,
+                true,
+            )
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle
+            .snapshot_diff("/tmp/fcs-diff.mem", "/tmp/fcs-diff.state", false)
+            .unwrap();
+        let base = fs::metadata("/tmp/fcs-base.mem").unwrap();
+        let diff = fs::metadata("/tmp/fcs-diff.mem").unwrap();
+        // A mostly idle guest dirties a fraction of its memory
+        assert!(diff.blocks() < base.blocks());
+        assert!(fs::metadata("/tmp/fcs-diff.state").is_ok());
+    }
+
+    #[test]
+    fn it_compacts_before_snapshots() {
+        let cpio_path = "my_compact_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- hang")
+            .initrd(File::open(cpio_path).unwrap())
+            .balloon(BalloonConfig {
+                amount_mib: 0,
+                deflate_on_oom: false,
+                stats_polling_interval_s: 1,
+            })
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        handle
+            .snapshot_compacted(
+                "/tmp/fcs-compact.mem",
+                "/tmp/fcs-compact.state",
+                SnapshotType::Full,
+                false,
+                64,
+                Duration::from_secs(10),
+            )
+            .unwrap();
+        assert!(handle.balloon_stats().unwrap().actual_mib >= 64);
+        assert!(fs::metadata("/tmp/fcs-compact.state").is_ok());
+
+        // Nothing is written when the guest cannot hand over the memory in time, here as it
+        // is paused
+        assert!(matches!(
+            handle.snapshot_compacted(
+                "/tmp/fcs-compact2.mem",
+                "/tmp/fcs-compact2.state",
+                SnapshotType::Full,
+                false,
+                128,
+                Duration::from_secs(2),
+            ),
+            Err(SpawnError::BalloonTimeout {
+                target_mib: 128,
+                ..
+            })
+        ));
+        assert!(fs::metadata("/tmp/fcs-compact2.state").is_err());
+    }
+
+    #[test]
+    fn it_restores_snapshots() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        handle
+            .snapshot(
+                "/tmp/fcs-restore.mem",
+                "/tmp/fcs-restore.state",
+                SnapshotType::Full,
+                false,
+            )
+            .unwrap();
+        drop(handle);
+
+        let restored = Vm::from_snapshot(
+            "/tmp/fcs-restore.mem",
+            "/tmp/fcs-restore.state",
+            RestoreConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(restored.wait().unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_forks_clones_from_a_template() {
+        let cpio_path = "my_template_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+        let template_dir = "/tmp/fcs-template";
+        fs::create_dir_all(template_dir).unwrap();
+        // Kept apart from the snapshot files, as clones have their own directory over it
+        let template_vsock_dir = Path::new("/tmp/fcs-template-vsock");
+        fs::create_dir_all(template_vsock_dir).unwrap();
+        let template_vsock = template_vsock_dir.join("v.sock");
+        let _ = fs::remove_file(&template_vsock);
+
+        let netns = NetNs::create().unwrap();
+        let tap = netns.run(|| Tap::create("fcstpl0", None)).unwrap().unwrap();
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init -- serve")
+            .initrd(File::open(cpio_path).unwrap())
+            .net(NetConfig {
+                tap_iface_name: "fcstpl0".to_string(),
+                ..Default::default()
+            })
+            .netns(netns.clone())
+            .vsock(VsockConfig::new(&template_vsock))
+            .ready_marker("Run /init as init process")
+            .build()
+            .unwrap();
+        let template = v
+            .clone_for_fork(Box::new(io::sink()), template_dir, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(
+            template.mem_path(),
+            Path::new("/tmp/fcs-template/template.mem")
+        );
+        netns.run(|| drop(tap)).unwrap();
+        fs::remove_file(&template_vsock).unwrap();
+
+        // Both clones run at once, each with the template's TAP name in a namespace of its
+        // own, and its own vsock directory
+        let clones: Vec<_> = ["clone-a", "clone-b"]
+            .into_iter()
+            .map(|id| {
+                let netns = NetNs::create().unwrap();
+                let tap = netns.run(|| Tap::create("fcstpl0", None)).unwrap().unwrap();
+                let vsock_dir = PathBuf::from(format!("/tmp/fcs-{id}"));
+                fs::create_dir_all(&vsock_dir).unwrap();
+                let clone = template
+                    .spawn(RestoreConfig {
+                        instance_id: Some(id.to_string()),
+                        netns: Some(netns.clone()),
+                        vsock_dir: Some((template_vsock.clone(), vsock_dir.clone())),
+                        ..Default::default()
+                    })
+                    .unwrap();
+                (clone, netns, tap, vsock_dir)
+            })
+            .collect();
+        assert!(!template_vsock.exists());
+        for (clone, _, _, vsock_dir) in &clones {
+            assert_eq!(clone.info().state, VmState::Running);
+            let vsock = VsockConfig::new(vsock_dir.join("v.sock"));
+            // The clone may not be listening yet
+            let mut stream = loop {
+                match vsock.host_connect(5000) {
+                    Ok(stream) => break stream,
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                        thread::sleep(Duration::from_millis(50))
+                    }
+                    Err(e) => panic!("{e}"),
+                }
+            };
+            stream.write_all(clone.id().as_bytes()).unwrap();
+            stream.write_all(b"\n").unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            assert_eq!(reply, format!("{}\n", clone.id()));
+        }
+        for (clone, netns, tap, vsock_dir) in clones {
+            assert_eq!(clone.wait().unwrap(), FcExitCode::Ok);
+            assert!(!vsock_dir.join("v.sock").exists());
+            netns.run(|| drop(tap)).unwrap();
+            fs::remove_dir(vsock_dir).unwrap();
+        }
+        fs::remove_dir_all(template_dir).unwrap();
+    }
+
+    #[test]
+    fn it_pauses_and_resumes() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        assert!(matches!(handle.resume(), Err(SpawnError::NotPaused)));
+        handle.pause().unwrap();
+        assert_eq!(handle.info().state, VmState::Paused);
+        assert!(matches!(handle.pause(), Err(SpawnError::AlreadyPaused)));
+        handle.resume().unwrap();
+        assert_eq!(handle.info().state, VmState::Running);
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_sets_instance_ids() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .instance_id("vm-1")
+            .app_name("tests")
+            .build()
+            .unwrap();
+        let info = v.instance_info();
+        assert_eq!(info.id, "vm-1");
+        assert_eq!(info.app_name, "tests");
+        assert_eq!(info.vmm_version, env!("CARGO_PKG_VERSION"));
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        assert_eq!(handle.id(), "vm-1");
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+
+        let v = Vm::builder()
+            .kernel(PathBuf::from("vmlinux"))
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        assert_ne!(v.instance_info().id, v.instance_info().id);
+    }
+
+    #[test]
+    fn it_loads_seccomp_filters() {
+        let filters = Seccomp::Default.filters().unwrap();
+        assert!(filters.contains_key("vmm"));
+        assert!(filters.contains_key("vcpu"));
+        assert!(Seccomp::None
+            .filters()
+            .unwrap()
+            .values()
+            .all(|f| f.is_empty()));
+        assert!(matches!(
+            Seccomp::Custom(PathBuf::from("does-not-exist.bpf")).filters(),
+            Err(SpawnError::SeccompFile { .. })
+        ));
+
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .seccomp(Seccomp::Default)
+            .build()
+            .unwrap();
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_builds_cmdlines() {
+        let cmdline = CmdlineBuilder::new()
+            .flag("quiet")
+            .param("panic", "-1")
+            .param("init", "/goinit")
+            .build()
+            .unwrap();
+        assert_eq!(cmdline, "quiet panic=-1 init=/goinit");
+
+        let with_ip = CmdlineBuilder::new()
+            .static_ip(
+                "eth0",
+                Ipv4Addr::new(172, 16, 0, 2),
+                Ipv4Addr::new(172, 16, 0, 1),
+                Ipv4Addr::new(255, 255, 255, 0),
+            )
+            .build()
+            .unwrap();
+        assert_eq!(with_ip, "ip=172.16.0.2::172.16.0.1:255.255.255.0::eth0:off");
+
+        assert!(matches!(
+            CmdlineBuilder::new().param("init", "/sbin/my init").build(),
+            Err(SpawnError::Cmdline(_))
+        ));
+        assert!(matches!(
+            CmdlineBuilder::new()
+                .capacity(8)
+                .param("init", "/goinit")
+                .build(),
+            Err(SpawnError::Cmdline(_))
+        ));
+
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline(cmdline)
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_keeps_the_console_tail() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .console_tail(5)
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        while handle.try_wait().unwrap().is_none() {
+            thread::sleep(Duration::from_millis(50));
+        }
+        let tail = handle.console_tail();
+        assert_eq!(tail.len(), 5);
+        assert!(tail.iter().all(|line| !line.ends_with(['\r', '\n'])));
+        handle.wait().unwrap();
+    }
+
+    #[test]
+    fn it_waits_for_ready_marker() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .ready_marker("Run /goinit as init process")
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        handle.wait_ready(Duration::from_secs(5)).unwrap();
+        // Once seen, the marker stays seen
+        handle.wait_ready(Duration::ZERO).unwrap();
+        assert!(handle.ready_after().unwrap() > Duration::ZERO);
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .ready_marker("never printed")
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        assert!(matches!(
+            handle.wait_ready(Duration::from_millis(100)),
+            Err(SpawnError::ReadyTimeout)
+        ));
+        assert_eq!(handle.ready_after(), None);
+    }
+
+    #[test]
+    fn it_attaches_boot_timer() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .boot_timer(true)
+            .build()
+            .unwrap();
+        assert!(v.resources("test", 3).unwrap().boot_timer);
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_keeps_boot_times_per_vcpu() {
+        // Logged from the vcpu thread the guest wrote to the boot timer on
+        // SAFETY: gettid has no preconditions.
+        let tid = unsafe { libc::gettid() };
+        let boot_time = BootTime::register(&[(tid, 0)]);
+        record_boot_time("Guest-boot-time =   1234 us 1 ms,    900 CPU us 0 CPU ms");
+        assert_eq!(boot_time.get(), Some(Duration::from_micros(1234)));
+        // Only the first value is kept
+        record_boot_time("Guest-boot-time =     99 us 0 ms,     80 CPU us 0 CPU ms");
+        assert_eq!(boot_time.get(), Some(Duration::from_micros(1234)));
+
+        let other = BootTime::register(&[(tid + 1, 0)]);
+        record_boot_time("Guest-boot-time =   5678 us 5 ms,    900 CPU us 0 CPU ms");
+        assert_eq!(other.get(), None);
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_restarts_on_reboot() {
+        let buf = SharedBuf::default();
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .reboot_policy(RebootPolicy::RestartUpTo(2))
+            .build()
+            .unwrap();
+        assert_eq!(v.make(Box::new(buf.clone())).unwrap(), FcExitCode::Ok);
+        let output = String::from_utf8_lossy(&buf.0.lock().unwrap()).to_string();
+        assert_eq!(output.matches("Linux version").count(), 3);
+    }
+
+    #[test]
+    fn it_writes_a_serial_banner() {
+        let buf = SharedBuf::default();
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .instance_id("vm-1")
+            .app_name("tests")
+            .serial_banner(true)
+            .build()
+            .unwrap();
+        assert_eq!(v.make(Box::new(buf.clone())).unwrap(), FcExitCode::Ok);
+        let output = String::from_utf8_lossy(&buf.0.lock().unwrap()).to_string();
+        assert!(output.starts_with("tests instance vm-1\n"));
+    }
+
+    #[test]
+    fn it_tees_serial_output() {
+        let (a, b) = (SharedBuf::default(), SharedBuf::default());
+        let tee = TeeSerialOut::new(vec![Box::new(a.clone()), Box::new(b.clone())]);
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(v.make(Box::new(tee)).unwrap(), FcExitCode::Ok);
+        let a = a.0.lock().unwrap().clone();
+        assert!(!a.is_empty());
+        assert_eq!(a, *b.0.lock().unwrap());
+    }
+
+    #[test]
+    fn it_keeps_teeing_past_failed_sinks() {
+        /// Fails every write
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("sink unavailable"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buf = SharedBuf::default();
+        let mut tee = TeeSerialOut::new(vec![Box::new(Broken), Box::new(buf.clone())]);
+        tee.write_all(b"hello\n").unwrap();
+        tee.write_all(b"world\n").unwrap();
+        // The healthy sink gets each byte once
+        assert_eq!(*buf.0.lock().unwrap(), b"hello\nworld\n");
+
+        let mut tee = TeeSerialOut::new(vec![Box::new(Broken), Box::new(Broken)]);
+        assert!(tee.write(b"hello\n").is_err());
+    }
+
+    #[test]
+    fn it_prefixes_serial_lines() {
+        let buf = SharedBuf::default();
+        let mut out = PrefixSerialOut::new("[vm-1] ", Box::new(buf.clone()));
+        out.write_all(b"hel").unwrap();
+        out.flush().unwrap();
+        assert!(buf.0.lock().unwrap().is_empty());
+        out.write_all(b"lo\nwor").unwrap();
+        out.write_all(b"ld\npartial").unwrap();
+        assert_eq!(*buf.0.lock().unwrap(), b"[vm-1] hello\n[vm-1] world\n");
+        drop(out);
+        assert_eq!(
+            *buf.0.lock().unwrap(),
+            b"[vm-1] hello\n[vm-1] world\n[vm-1] partial\n"
+        );
+    }
+
+    #[test]
+    fn it_bounds_panic_report_lines() {
+        let panic = Arc::new(GuestPanic::default());
+        let mut out = PanicWatcher::new(Box::new(io::sink()), panic.clone());
+        out.write_all(b"Kernel panic - not syncing: oops\n")
+            .unwrap();
+        out.write_all(&[b'x'; 10_000]).unwrap();
+        out.write_all(b"\n---[ end Kernel panic - not syncing: oops ]---\n")
+            .unwrap();
+        let report = panic.report().unwrap();
+        assert_eq!(report.message, "oops");
+        assert_eq!(report.backtrace_lines[0].len(), 4096);
+    }
+
+    #[test]
+    fn it_bounds_console_tail_lines() {
+        let tail = Arc::new(ConsoleTail::new(2));
+        let mut out = TailWatcher::new(Box::new(io::sink()), tail.clone());
+        out.write_all(&[b'x'; 10_000]).unwrap();
+        out.write_all(b"\nok\n").unwrap();
+        let lines = tail.lines();
+        assert_eq!(lines[0].len(), 4096);
+        assert_eq!(lines[1], "ok");
+    }
+
+    #[test]
+    fn it_waits_for_serial_patterns() {
+        let waiters = Arc::new(SerialWaiters::default());
+        let mut out = SerialMatcher::new(Box::new(io::sink()), waiters.clone());
+        // Lines written before anyone waits are not matched
+        out.write_all(b"READY 0\n").unwrap();
+        let waiting = waiters.clone();
+        let ready = thread::spawn(move || {
+            waiting.wait(&Regex::new(r"^READY \d+$").unwrap(), Duration::from_secs(5))
+        });
+        let waiting = waiters.clone();
+        let login = thread::spawn(move || {
+            waiting.wait(&Regex::new("login: $").unwrap(), Duration::from_secs(5))
+        });
+        thread::sleep(Duration::from_millis(100));
+        out.write_all(b"booting\r\nREA").unwrap();
+        out.write_all(b"DY 42\r\nvm login: ").unwrap();
+        assert_eq!(ready.join().unwrap().as_deref(), Some("READY 42"));
+        // Prompts are matched before their line ends
+        assert_eq!(login.join().unwrap().as_deref(), Some("vm login:"));
+        assert_eq!(
+            waiters.wait(&Regex::new("never").unwrap(), Duration::from_millis(50)),
+            None
+        );
+    }
+
+    #[test]
+    fn it_splits_serial_output_into_lines() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let seen = lines.clone();
+        let mut out = LineSerialOut::new(move |line| seen.lock().unwrap().push(line.to_string()));
+        out.write_all(b"Linux vers").unwrap();
+        out.write_all(b"ion 6.1\r\nready\n\xffpartial").unwrap();
+        assert_eq!(*lines.lock().unwrap(), ["Linux version 6.1", "ready"]);
+        drop(out);
+        assert_eq!(
+            *lines.lock().unwrap(),
+            ["Linux version 6.1", "ready", "\u{fffd}partial"]
+        );
+    }
+
+    #[test]
+    fn it_writes_serial_output_to_a_pty() {
+        let mut out = PtySerialOut::new().unwrap();
+        assert!(out.path().starts_with("/dev/pts"));
+        let mut terminal = File::open(out.path()).unwrap();
+        out.write_all(b"hello\n").unwrap();
+        let mut buf = [0; 6];
+        terminal.read_exact(&mut buf).unwrap();
+        // Raw mode: no `\r` added before the newline
+        assert_eq!(&buf, b"hello\n");
+    }
+
+    #[test]
+    fn it_retries_prefixed_lines_after_errors() {
+        /// Fails its first write
+        struct Flaky(bool, SharedBuf);
+
+        impl Write for Flaky {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if !std::mem::replace(&mut self.0, true) {
+                    return Err(io::Error::other("sink unavailable"));
+                }
+                self.1.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buf = SharedBuf::default();
+        let mut out = PrefixSerialOut::new("> ", Box::new(Flaky(false, buf.clone())));
+        // The serial device writes what was not taken again
+        let taken = out.write(b"ab\ncd\n").unwrap();
+        assert_eq!(taken, 2);
+        out.write_all(&b"ab\ncd\n"[taken..]).unwrap();
+        assert_eq!(*buf.0.lock().unwrap(), b"> ab\n> cd\n");
+    }
+
+    #[test]
+    fn it_resumes_partly_written_prefixed_lines() {
+        /// Takes 3 bytes, then fails once
+        struct Short(usize, SharedBuf);
+
+        impl Write for Short {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0 += 1;
+                match self.0 {
+                    1 => self.1.write(&buf[..3]),
+                    2 => Err(io::Error::other("sink unavailable")),
+                    _ => self.1.write(buf),
+                }
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buf = SharedBuf::default();
+        let mut out = PrefixSerialOut::new("> ", Box::new(Short(0, buf.clone())));
+        let taken = out.write(b"abc\n").unwrap();
+        assert_eq!(taken, 3);
+        out.write_all(&b"abc\n"[taken..]).unwrap();
+        // Nothing `inner` took is written again
+        assert_eq!(*buf.0.lock().unwrap(), b"> abc\n");
+    }
+
+    #[test]
+    fn it_writes_metrics() {
+        let buf = SharedBuf::default();
+        init_metrics(Box::new(buf.clone()), Duration::from_millis(50)).unwrap();
+        assert!(matches!(
+            init_metrics(Box::new(io::sink()), Duration::from_millis(50)),
+            Err(SpawnError::Metrics(_))
+        ));
+
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+        thread::sleep(Duration::from_millis(200));
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let line = out.lines().next().expect("no metrics were flushed");
+        let metrics: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert!(metrics.get("block").is_some());
+    }
+
+    #[test]
+    fn it_shuts_down_from_another_thread() {
+        let cpio_path = "my_hang_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- hang")
+            .initrd(File::open(cpio_path).unwrap())
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        let signal = handle.shutdown_signal();
+        let supervisor = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            signal.shutdown();
+        });
+        assert!(matches!(handle.wait(), Err(SpawnError::Terminated)));
+        supervisor.join().unwrap();
+    }
+
+    #[test]
+    fn it_polls_for_exit() {
+        let cpio_path = "my_try_wait_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- hang")
+            .initrd(File::open(cpio_path).unwrap())
+            .build()
+            .unwrap();
+        let handle = v.spawn(Box::new(io::sink())).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(matches!(handle.try_wait(), Ok(None)));
+        handle.kill();
+        assert!(matches!(handle.try_wait(), Err(SpawnError::Terminated)));
+        assert!(matches!(handle.wait(), Err(SpawnError::Terminated)));
+
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        let exit_code = loop {
+            if let Some(exit_code) = handle.try_wait().unwrap() {
+                break exit_code;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert_eq!(exit_code, FcExitCode::Ok);
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_times_out() {
+        let cpio_path = "my_timeout_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- hang")
+            .initrd(File::open(cpio_path).unwrap())
+            .timeout(Duration::from_secs(1))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            v.make(Box::new(io::sink())),
+            Err(SpawnError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn it_pins_vcpus() {
+        let mut online: Vec<usize> = affinity::online_cpus().unwrap().into_iter().collect();
+        online.sort();
+        // Distinct CPUs where the host has them
+        let pinned = vec![online[0], *online.get(1).unwrap_or(&online[0])];
+        let cpio_path = "my_pinned_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+        let v = Vm::builder()
+            .vcpus(2)
+            .vcpu_affinity(pinned.clone())
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- hang")
+            .initrd(File::open(cpio_path).unwrap())
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        assert_eq!(handle.vcpu_threads().len(), 2);
+        for &(tid, index) in handle.vcpu_threads() {
+            let comm = fs::read_to_string(format!("/proc/self/task/{tid}/comm")).unwrap();
+            assert_eq!(comm.trim_end(), format!("fc_vcpu {index}"));
+            // SAFETY: `set` is a plain bitmask, zeroed before use.
+            let cpus: Vec<usize> = unsafe {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                let ret =
+                    libc::sched_getaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+                assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+                (0..libc::CPU_SETSIZE as usize)
+                    .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+                    .collect()
+            };
+            assert_eq!(cpus, vec![pinned[index]]);
+        }
+        handle.shutdown();
+        assert!(matches!(handle.wait(), Err(SpawnError::Terminated)));
+    }
+
+    #[test]
+    fn it_reports_vcpu_cpu_time() {
+        let cpio_path = "my_cpu_stats_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+        let v = Vm::builder()
+            .vcpus(2)
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- hang")
+            .initrd(File::open(cpio_path).unwrap())
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        let before = handle.cpu_stats().unwrap();
+        assert_eq!(
+            before.iter().map(|t| t.vcpu).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        // Booting the kernel takes CPU time
+        assert!(before.iter().any(|t| t.user + t.system > Duration::ZERO));
+        let after = handle.cpu_stats().unwrap();
+        for (before, after) in before.iter().zip(&after) {
+            assert!(after.user >= before.user && after.system >= before.system);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn it_runs_async() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        let exit_code = rt.block_on(v.start_async(Box::new(io::sink())));
+        assert_eq!(exit_code.unwrap(), FcExitCode::Ok);
+
+        let cpio_path = "my_async_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- hang")
+            .initrd(File::open(cpio_path).unwrap())
+            .build()
+            .unwrap();
+        let cancelled = rt.block_on(tokio::time::timeout(
+            Duration::from_secs(1),
+            v.start_async(Box::new(io::sink())),
+        ));
+        assert!(cancelled.is_err());
+    }
+
+    #[test]
+    fn it_boots_from_memory() {
+        let initrd = fs::read("bootstrap-initrd.cpio.gz").unwrap();
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(memfd("initrd", initrd.as_slice()).unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+
+        let rootfs = File::open("rootfs.ext4").unwrap();
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                read_only: true,
+                ..Disk::from_file(&rootfs)
+            })
+            .build()
+            .unwrap();
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_reports_info() {
+        let v = Vm::builder()
+            .vcpus(2)
+            .mem_mib(64)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .add_disk(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .instance_id("info-vm")
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        let info = handle.info();
+        assert_eq!(info.id, "info-vm");
+        assert_eq!(info.vcpu_count, 2);
+        assert_eq!(info.mem_size_mib, 64);
+        assert_eq!(info.drive_ids, vec!["block0", "block1"]);
+        assert!(info.net_ids.is_empty());
+        assert!(!info.vsock);
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+    }
+
+    #[test]
+    fn it_stops_on_drop() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        drop(handle);
+    }
+
+    #[test]
+    fn it_requires_a_boot_device() {
+        assert!(matches!(
+            Vm::builder()
+                .mem_mib(32)
+                .kernel(PathBuf::from("vmlinux"))
+                .cmdline("panic=-1 reboot=t")
+                .build(),
+            Err(SpawnError::NoBootDevice)
+        ));
+    }
+
+    #[test]
+    fn it_boots_from_root_in_cmdline() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t root=/dev/vda init=/goinit")
+            .add_disk(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+
+        assert!(matches!(
+            VmBuilder::new()
+                .kernel(File::open("vmlinux").unwrap())
+                .cmdline("panic=-1 -- root=/dev/vda")
+                .build(),
+            Err(SpawnError::NoBootDevice)
+        ));
+    }
 
-pub struct Vm {
-    pub vcpu_count: u8,
-    pub mem_size_mib: usize,
-    pub kernel: File,
-    pub kernel_cmdline: String,
-    pub vsock: Option<String>,
-    pub initrd: Option<File>,
-    pub rootfs: Option<Disk>,
-    pub extra_disks: Vec<Disk>,
-    pub net_config: Option<NetConfig>,
-    pub use_hugepages: bool,
-}
+    #[test]
+    fn it_loads_config_files() {
+        let path = "/tmp/fcs-config.json";
+        fs::write(
+            path,
+            r#"{
+                "boot-source": {
+                    "kernel_image_path": "vmlinux",
+                    "boot_args": "quiet panic=-1 reboot=t init=/goinit"
+                },
+                "drives": [
+                    {
+                        "drive_id": "rootfs",
+                        "path_on_host": "rootfs.ext4",
+                        "is_root_device": true,
+                        "is_read_only": false,
+                        "cache_type": "Writeback"
+                    }
+                ],
+                "machine-config": {
+                    "vcpu_count": 2,
+                    "mem_size_mib": 64
+                }
+            }"#,
+        )
+        .unwrap();
+        let v = Vm::from_config_file(path).unwrap();
+        assert_eq!(v.vcpu_count, 2);
+        assert_eq!(v.mem_size_mib, 64);
+        let rootfs = v.rootfs.as_ref().unwrap();
+        assert_eq!(rootfs.path, PathBuf::from("rootfs.ext4"));
+        assert_eq!(rootfs.cache_type, Some(CacheType::Writeback));
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
 
-impl Vm {
-    pub fn make(&self, output: Box<dyn SerialOut>) -> Result<(), Box<dyn Error>> {
-        let instance_info = InstanceInfo {
-            id: "anonymous-instance".to_string(),
-            state: VmState::NotStarted,
-            vmm_version: "Amazing version".to_string(),
-            app_name: "cpu-template-helper".to_string(),
-        };
+        fs::write(
+            path,
+            r#"{"boot-source": {"kernel_image_path": "vmlinux"}, "logger": {}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            Vm::from_config_file(path),
+            Err(SpawnError::ConfigParse { .. })
+        ));
+    }
 
-        let vm_config = VmConfig {
-            vcpu_count: self.vcpu_count,
-            mem_size_mib: self.mem_size_mib,
-            smt: false,
-            cpu_template: None,
-            track_dirty_pages: false,
-            huge_pages: if self.use_hugepages {
-                HugePageConfig::Hugetlbfs2M
-            } else {
-                HugePageConfig::None
-            },
-        };
-        let initrd = match &self.initrd {
-            None => None,
-            Some(f) => Some(f.try_clone()?),
-        };
-        let boot_source = BootSource {
-            config: BootSourceConfig::default(),
-            builder: Some(BootConfig {
-                cmdline: linux_loader::cmdline::Cmdline::try_from(&self.kernel_cmdline, 4096)?,
-                kernel_file: self.kernel.try_clone()?,
-                initrd_file: initrd,
-            }),
-        };
+    #[test]
+    fn it_builds() {
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(v.vcpu_count, 1);
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
 
-        let mut net_builder = NetBuilder::new();
-        match &self.net_config {
-            Some(nc) => {
-                let mac = nc.vm_mac.unwrap_or([0x0, 0x2, 0x0, 0x0, 0x0, 0x0]);
-                net_builder
-                    .build(NetworkInterfaceConfig {
-                        iface_id: "net0".to_string(),
-                        host_dev_name: nc.tap_iface_name.clone(),
-                        guest_mac: Some(MacAddr::from_bytes_unchecked(&mac)),
-                        rx_rate_limiter: None,
-                        tx_rate_limiter: None,
-                    })
-                    .unwrap();
-            }
-            None => (),
-        };
+        assert!(matches!(
+            VmBuilder::new().build(),
+            Err(SpawnError::MissingKernel)
+        ));
+        assert!(matches!(
+            VmBuilder::new()
+                .kernel(File::open("vmlinux").unwrap())
+                .build(),
+            Err(SpawnError::NoBootDevice)
+        ));
+    }
 
-        let mut block = BlockBuilder::new();
+    #[test]
+    fn it_checks_host_memory() {
+        let v = Vm::builder()
+            .mem_mib(64 * 1024 * 1024)
+            .kernel(File::open("vmlinux").unwrap())
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        assert!(matches!(
+            v.validate(),
+            Err(SpawnError::InsufficientMemory { requested, available })
+                if requested == 64 * 1024 * 1024 && available < requested
+        ));
 
-        if let Some(rootfs) = &self.rootfs {
-            block
-                .insert(BlockDeviceConfig {
-                    drive_id: "block0".to_string(),
-                    partuuid: None,
-                    is_root_device: true,
-                    cache_type: CacheType::Unsafe,
+        let v = Vm::builder()
+            .mem_mib(64 * 1024 * 1024)
+            .allow_overcommit(true)
+            .kernel(File::open("vmlinux").unwrap())
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build()
+            .unwrap();
+        assert!(!matches!(
+            v.validate(),
+            Err(SpawnError::InsufficientMemory { .. })
+        ));
+    }
+
+    #[test]
+    fn it_validates() {
+        assert!(matches!(
+            Vm::builder()
+                .mem_mib(0)
+                .kernel(File::open("vmlinux").unwrap())
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .build(),
+            Err(SpawnError::ZeroMemSize)
+        ));
+
+        assert!(matches!(
+            Vm::builder()
+                .mem_mib(33)
+                .huge_pages(HugePages::Hugetlbfs2M)
+                .kernel(File::open("vmlinux").unwrap())
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .build(),
+            Err(SpawnError::InvalidMemSize {
+                mem_size_mib: 33,
+                page_size_mib: 2
+            })
+        ));
+
+        assert!(matches!(
+            Vm::builder()
+                .mem_mib(1024)
+                .huge_pages(HugePages::Hugetlbfs1G)
+                .kernel(File::open("vmlinux").unwrap())
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .build(),
+            Err(SpawnError::UnsupportedHugePages {
+                page_size_mib: 1024
+            })
+        ));
+
+        assert!(matches!(
+            Vm::builder()
+                .vcpus(0)
+                .kernel(File::open("vmlinux").unwrap())
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .build(),
+            Err(SpawnError::InvalidVcpuCount {
+                requested: 0,
+                max: 32
+            })
+        ));
 
-                    is_read_only: Some(rootfs.read_only),
-                    path_on_host: Some(rootfs.path.as_path().display().to_string()),
-                    rate_limiter: None,
-                    file_engine_type: None,
+        assert!(matches!(
+            Vm::builder()
+                .vcpus(33)
+                .kernel(File::open("vmlinux").unwrap())
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .build(),
+            Err(SpawnError::InvalidVcpuCount {
+                requested: 33,
+                max: 32
+            })
+        ));
 
-                    socket: None,
+        assert!(matches!(
+            Vm::builder()
+                .vcpus(2)
+                .vcpu_affinity(vec![0])
+                .kernel(File::open("vmlinux").unwrap())
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .build(),
+            Err(SpawnError::VcpuAffinityLength {
+                len: 1,
+                vcpu_count: 2
+            })
+        ));
+
+        assert!(matches!(
+            Vm::builder()
+                .vcpu_affinity(vec![usize::MAX])
+                .kernel(File::open("vmlinux").unwrap())
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .build(),
+            Err(SpawnError::CpuOffline { cpu: usize::MAX })
+        ));
+
+        assert!(matches!(
+            Vm::builder()
+                .kernel(File::open("vmlinux").unwrap())
+                .rootfs(Disk {
+                    path: PathBuf::from("does-not-exist.ext4"),
+                    ..Default::default()
                 })
-                .unwrap();
-        };
+                .build(),
+            Err(SpawnError::DiskNotFound { .. })
+        ));
 
-        for (i, disk) in self.extra_disks.iter().enumerate() {
-            block
-                .insert(BlockDeviceConfig {
-                    drive_id: format!("block{}", i + 0),
-                    partuuid: None,
-                    is_root_device: false,
-                    cache_type: CacheType::Unsafe,
+        assert!(matches!(
+            Vm::builder()
+                .kernel(File::open("vmlinux").unwrap())
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .cmdline("a".repeat(64))
+                .cmdline_capacity(64)
+                .build(),
+            Err(SpawnError::CmdlineTooLong {
+                len: 64,
+                capacity: 64
+            })
+        ));
+
+        assert!(matches!(
+            Vm::builder()
+                .kernel(File::open("vmlinux").unwrap())
+                .rootfs(Disk {
+                    path: PathBuf::from("rootfs.ext4"),
+                    socket: Some(PathBuf::from("vhost-user.sock")),
+                    ..Default::default()
+                })
+                .build(),
+            Err(SpawnError::DiskBackend { .. })
+        ));
 
-                    is_read_only: Some(disk.read_only),
-                    path_on_host: Some(disk.path.as_path().display().to_string()),
-                    rate_limiter: None,
-                    file_engine_type: None,
+        assert!(matches!(
+            Vm::builder()
+                .kernel(File::open("vmlinux").unwrap())
+                .rootfs(Disk::default())
+                .build(),
+            Err(SpawnError::DiskBackend { .. })
+        ));
 
-                    socket: None,
+        assert!(matches!(
+            Vm::builder()
+                .kernel(File::open("vmlinux").unwrap())
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .net(NetConfig::default())
+                .build(),
+            Err(SpawnError::EmptyTapName)
+        ));
+
+        assert!(matches!(
+            Vm::builder()
+                .kernel(File::open("vmlinux").unwrap())
+                .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+                .net(NetConfig {
+                    tap_iface_name: "doesnotexist0".to_string(),
+                    ..Default::default()
                 })
-                .unwrap();
+                .build(),
+            Err(SpawnError::TapNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn it_rejects_busy_taps() {
+        let net = NetConfig {
+            tap_iface_name: "mytap1".to_string(),
+            ..Default::default()
+        };
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .net(net.clone())
+            .build()
+            .unwrap();
+        v.validate().unwrap();
+        let _handle = v.start(Box::new(io::sink())).unwrap();
+        match v.validate() {
+            Err(SpawnError::TapBusy { name }) => assert_eq!(name, "mytap1"),
+            _ => panic!("expected a busy TAP"),
         }
+    }
 
-        let mut vsock = VsockBuilder::new();
-        if let Some(ref vpath) = self.vsock {
-            let cfg = VsockDeviceConfig {
-                vsock_id: None,
-                guest_cid: 3,
-                uds_path: vpath.clone(),
-            };
-            vsock.insert(cfg).unwrap();
+    #[test]
+    fn it_creates_taps() {
+        let tap = Tap::create("fcstap0", None).unwrap();
+        tap.set_address(Ipv4Addr::new(172, 16, 0, 1), 30).unwrap();
+        tap.set_address6("fd00:fc:1::1".parse().unwrap(), 64)
+            .unwrap();
+        assert!(matches!(
+            Tap::create("fcstap0", None),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists
+        ));
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/goinit")
+            .rootfs(Disk {
+                path: PathBuf::from("rootfs.ext4"),
+                read_only: true,
+                ..Default::default()
+            })
+            .net(NetConfig {
+                tap_iface_name: tap.name().to_string(),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        v.validate().unwrap();
+        drop(tap);
+        assert!(!Path::new("/sys/class/net/fcstap0").exists());
+    }
+
+    #[test]
+    fn it_owns_taps() {
+        let tap = Tap::create("fcsowntap0", None).unwrap();
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .net(NetConfig {
+                tap_iface_name: tap.name().to_string(),
+                ..Default::default()
+            })
+            .own_tap(tap)
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+        // The handle keeps the TAP once the `Vm` is gone
+        drop(v);
+        assert!(Path::new("/sys/class/net/fcsowntap0").exists());
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+        assert!(!Path::new("/sys/class/net/fcsowntap0").exists());
+    }
+
+    #[test]
+    fn it_runs_in_a_netns() {
+        let netns = NetNs::create().unwrap();
+        let tap = netns
+            .run(|| Tap::create("fcsnstap0", None))
+            .unwrap()
+            .unwrap();
+        assert!(Tap::create("fcsnstap0", None).is_ok());
+
+        let v = Vm::builder()
+            .mem_mib(32)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("panic=-1 reboot=t init=/init")
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .net(NetConfig {
+                tap_iface_name: "fcsnstap0".to_string(),
+                ..Default::default()
+            })
+            .netns(netns.clone())
+            .build()
+            .unwrap();
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
+        netns.run(|| drop(tap)).unwrap();
+    }
+
+    #[test]
+    fn it_connects_netns_with_veth() {
+        let netns = NetNs::create().unwrap();
+        let veth = netns
+            .veth("fcsveth0", "eth0", Ipv4Addr::new(172, 31, 0, 0), 30)
+            .unwrap();
+        assert_eq!(veth.host_name(), "fcsveth0");
+        assert_eq!(veth.gateway(), Ipv4Addr::new(172, 31, 0, 1));
+        assert_eq!(veth.address(), Ipv4Addr::new(172, 31, 0, 2));
+        // /sys/class/net shows the host's interfaces, /proc/thread-self/net the thread's
+        let ns_dev = netns
+            .run(|| fs::read_to_string("/proc/thread-self/net/dev"))
+            .unwrap()
+            .unwrap();
+        assert!(ns_dev.contains("eth0:"));
+        assert!(Path::new("/sys/class/net/fcsveth0").exists());
+        drop(veth);
+        assert!(!Path::new("/sys/class/net/fcsveth0").exists());
+    }
+
+    #[test]
+    fn it_sets_up_nat() {
+        assert!(matches!(
+            HostNetwork::nat("fcsnat0", Ipv4Addr::new(172, 30, 0, 0), 31),
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput
+        ));
+        let mut net = HostNetwork::nat("fcsnat0", Ipv4Addr::new(172, 30, 0, 7), 24).unwrap();
+        assert_eq!(net.gateway(), Some(Ipv4Addr::new(172, 30, 0, 1)));
+        assert_eq!(net.gateway6(), None);
+        net.nat6("fd00:fc::".parse().unwrap(), 64).unwrap();
+        assert_eq!(net.gateway6(), Some("fd00:fc::1".parse().unwrap()));
+        assert_eq!(net.tap().name(), "fcsnat0");
+        assert_eq!(
+            fs::read_to_string("/proc/sys/net/ipv4/ip_forward").unwrap(),
+            "1\n"
+        );
+        drop(net);
+        assert!(!Path::new("/sys/class/net/fcsnat0").exists());
+    }
+
+    #[test]
+    fn it_restores_forwarding() {
+        // In a namespace of its own, so other tests' networks don't keep forwarding on
+        NetNs::create()
+            .unwrap()
+            .run(|| {
+                let forward = || fs::read_to_string("/proc/sys/net/ipv4/ip_forward").unwrap();
+                let before = forward();
+                let a = HostNetwork::nat("fcsfwd0", Ipv4Addr::new(172, 31, 0, 0), 24).unwrap();
+                let b = HostNetwork::nat("fcsfwd1", Ipv4Addr::new(172, 31, 1, 0), 24).unwrap();
+                assert_eq!(forward(), "1\n");
+                // Still needed by `b`
+                drop(a);
+                assert_eq!(forward(), "1\n");
+                drop(b);
+                assert_eq!(forward(), before);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn it_reports_kernel_path() {
+        let v = Vm::builder()
+            .kernel(PathBuf::from("does-not-exist"))
+            .initrd(File::open("bootstrap-initrd.cpio.gz").unwrap())
+            .build();
+        match v {
+            Err(SpawnError::Kernel {
+                path: Some(path), ..
+            }) => assert_eq!(path, PathBuf::from("does-not-exist")),
+            _ => panic!("expected a kernel error"),
         }
+    }
 
-        let vm_resources = VmResources {
-            vm_config,
-            boot_source,
-            net_builder,
-            block,
-            boot_timer: false,
-            vsock,
-            ..Default::default()
-        };
+    /// Packs the `vsock-bin` test binary as `/init` into a cpio archive at `cpio_path`.
+    fn build_vsock_bin_initrd(cpio_path: &str) {
+        let test_bin_path = TestBinary::relative_to_parent(
+            "vsock-bin",
+            &PathBuf::from_iter(["testbins", "vsock-bin", "Cargo.toml"]),
+        )
+        .with_target("x86_64-unknown-linux-musl")
+        .build()
+        .unwrap();
+        let init_bytes = fs::read(test_bin_path).unwrap();
+        InitrdBuilder::new()
+            .init(init_bytes)
+            .write(File::create(cpio_path).unwrap())
+            .unwrap();
+    }
 
-        let mut event_manager = EventManager::new().unwrap();
-        let seccomp_filters = get_empty_filters();
+    #[test]
+    fn it_builds_initrds() {
+        let initrd = InitrdBuilder::new()
+            .init(b"#!/bin/sh".to_vec())
+            .file("/etc/app/config.json", "{}", 0o600)
+            .dir("/proc", 0o555);
+        let mut archive = Vec::new();
+        initrd.write(&mut archive).unwrap();
 
-        let vm = build_microvm_for_boot(
-            &instance_info,
-            &vm_resources,
-            &mut event_manager,
-            &seccomp_filters,
-            output,
-        )?;
-        vm.lock().unwrap().resume_vm()?;
+        let mut entries = vec![];
+        let mut rest = archive.as_slice();
         loop {
-            event_manager.run().unwrap();
-            match vm.lock().unwrap().shutdown_exit_code() {
-                Some(FcExitCode::Ok) => break,
-                Some(_) => {
-                    println!("vm died??");
-                    return Ok(());
-                }
-                None => continue,
+            let mut entry = NewcReader::new(rest).unwrap();
+            if entry.entry().is_trailer() {
+                break;
             }
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            entries.push((
+                entry.entry().name().to_string(),
+                entry.entry().mode(),
+                contents,
+            ));
+            rest = entry.finish().unwrap();
         }
-        Ok(())
+        assert_eq!(
+            entries,
+            vec![
+                ("init".to_string(), 0o100755, "#!/bin/sh".to_string()),
+                ("etc".to_string(), 0o40755, String::new()),
+                ("etc/app".to_string(), 0o40755, String::new()),
+                (
+                    "etc/app/config.json".to_string(),
+                    0o100600,
+                    "{}".to_string()
+                ),
+                ("proc".to_string(), 0o40555, String::new()),
+            ]
+        );
+
+        let file = initrd.build().unwrap();
+        assert_eq!(file.metadata().unwrap().len(), archive.len() as u64);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{Disk, NetConfig, Vm};
-    use cpio::{newc, NewcBuilder};
-    use std::fs::{self, File};
-    use std::io::{Read, Write};
-    use std::os::unix::net::UnixListener;
-    use std::path::PathBuf;
-    use std::{io, thread};
-    use test_binary::TestBinary;
     #[test]
-    fn it_works_net() {
-        let kernel = File::open("vmlinux").unwrap();
-        let v = Vm {
-            vcpu_count: 1,
-            mem_size_mib: 32,
-            kernel,
-            kernel_cmdline: "quiet panic=-1 reboot=t init=/goinit".to_string(),
-            rootfs: Some(Disk {
-                path: PathBuf::from("rootfs.ext4"),
-                read_only: false,
-            }),
-            initrd: None,
-            extra_disks: vec![],
-            net_config: Some(NetConfig {
-                tap_iface_name: "mytap0".to_string(),
-                vm_mac: None,
-            }),
-            use_hugepages: false,
-            vsock: None,
+    fn it_adopts_inherited_listeners() {
+        let vsock = VsockConfig::new("/tmp/test-fd.v.sock");
+        let _ = fs::remove_file(vsock.listener_path(1234));
+        let bound = UnixListener::bind(vsock.listener_path(1234)).unwrap();
+        let fd = OwnedFd::from(bound.try_clone().unwrap());
+        let listener = vsock.listener_from_fd(1234, fd).unwrap();
+        assert_eq!(
+            listener.local_addr().unwrap().as_pathname(),
+            Some(Path::new("/tmp/test-fd.v.sock_1234"))
+        );
+
+        let fd = OwnedFd::from(bound);
+        let err = vsock.listener_from_fd(1235, fd).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_file(vsock.listener_path(1234)).unwrap();
+    }
+
+    #[test]
+    fn it_works_ctrl_alt_del() {
+        let cpio_path = "my_cad_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+
+        let vsock_path = "/tmp/test-cad.v.sock";
+        let _ = fs::remove_file(vsock_path);
+        let vsock = VsockConfig::new(vsock_path);
+        let listener = vsock.listen(1234).unwrap();
+
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- cad")
+            .initrd(File::open(cpio_path).unwrap())
+            .vsock(vsock)
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+
+        let read_message = || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = String::new();
+            stream.read_to_string(&mut buf).unwrap();
+            buf
         };
-        v.make(Box::new(io::sink())).unwrap();
+        assert_eq!(read_message(), "READY\n");
+        handle.send_ctrl_alt_del().unwrap();
+        assert_eq!(read_message(), "CAD\n");
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
     }
 
     #[test]
-    fn it_works_disk() {
-        let kernel = File::open("vmlinux").unwrap();
-        let v = Vm {
-            vcpu_count: 1,
-            mem_size_mib: 32,
-            kernel,
-            kernel_cmdline: "quiet panic=-1 reboot=t init=/goinit".to_string(),
-            rootfs: Some(Disk {
-                path: PathBuf::from("rootfs.ext4"),
-                read_only: false,
-            }),
-            initrd: None,
-            extra_disks: vec![Disk {
-                path: PathBuf::from("/home/david/git/lk/disk.tar.gz"),
-                read_only: true,
-            }],
-            net_config: None,
-            use_hugepages: false,
-            vsock: None,
+    fn it_shuts_down_gracefully() {
+        let cpio_path = "my_graceful_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+
+        for (mode, exits) in [("cad", true), ("nocad", false)] {
+            let vsock_path = format!("/tmp/test-graceful-{mode}.v.sock");
+            let _ = fs::remove_file(&vsock_path);
+            let vsock = VsockConfig::new(&vsock_path);
+            let listener = vsock.listen(1234).unwrap();
+
+            let v = Vm::builder()
+                .mem_mib(256)
+                .kernel(PathBuf::from("vmlinux"))
+                .cmdline(format!("quiet panic=-1 reboot=t init=/init -- {mode}"))
+                .initrd(File::open(cpio_path).unwrap())
+                .vsock(vsock)
+                .build()
+                .unwrap();
+            let handle = v.start(Box::new(io::sink())).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = String::new();
+            stream.read_to_string(&mut buf).unwrap();
+            assert_eq!(buf, "READY\n");
+
+            let result = handle.shutdown_graceful(Duration::from_secs(2));
+            if exits {
+                assert_eq!(result.unwrap(), FcExitCode::Ok);
+            } else {
+                assert!(matches!(result, Err(SpawnError::Terminated)));
+            }
+        }
+    }
+
+    #[test]
+    fn it_keeps_disk_writes_across_teardown() {
+        let cpio_path = "my_persist_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+        let disk_path = "/tmp/fcs-persist.img";
+        File::create(disk_path)
+            .unwrap()
+            .set_len(1024 * 1024)
+            .unwrap();
+
+        let vsock_path = "/tmp/test-persist.v.sock";
+        let _ = fs::remove_file(vsock_path);
+        let vsock = VsockConfig::new(vsock_path);
+        let listener = vsock.listen(1234).unwrap();
+        let vm = |mode: &str| {
+            Vm::builder()
+                .mem_mib(256)
+                .kernel(PathBuf::from("vmlinux"))
+                .cmdline(format!("quiet panic=-1 reboot=t init=/init -- {mode}"))
+                .initrd(File::open(cpio_path).unwrap())
+                .add_disk(Disk {
+                    path: PathBuf::from(disk_path),
+                    cache_type: Some(CacheType::Writeback),
+                    ..Default::default()
+                })
+                .vsock(vsock.clone())
+                .build()
+                .unwrap()
         };
-        v.make(Box::new(io::sink())).unwrap();
+        let read_message = || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = String::new();
+            stream.read_to_string(&mut buf).unwrap();
+            buf
+        };
+
+        let handle = vm("write").start(Box::new(io::sink())).unwrap();
+        assert_eq!(read_message(), "WRITTEN\n");
+        drop(handle);
+
+        let handle = vm("read").start(Box::new(io::sink())).unwrap();
+        assert_eq!(read_message(), "PERSISTED\n");
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+        fs::remove_file(disk_path).unwrap();
     }
 
     #[test]
-    fn it_works_initrd() {
-        let kernel = File::open("vmlinux").unwrap();
-        let v = Vm {
-            vcpu_count: 1,
-            mem_size_mib: 32,
-            kernel,
-            kernel_cmdline: "panic=-1 reboot=t init=/init".to_string(),
-            rootfs: None,
-            initrd: Some(File::open("bootstrap-initrd.cpio.gz").unwrap()),
-            extra_disks: vec![],
-            net_config: None,
-            use_hugepages: false,
-            vsock: None,
+    fn it_connects_to_guest_vsock_ports() {
+        let cpio_path = "my_serve_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+
+        let vsock_path = "/tmp/test-serve.v.sock";
+        let _ = fs::remove_file(vsock_path);
+        let vsock = VsockConfig::new(vsock_path);
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- serve")
+            .initrd(File::open(cpio_path).unwrap())
+            .vsock(vsock.clone())
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+
+        // The guest may not be listening yet
+        let mut stream = loop {
+            match vsock.host_connect(5000) {
+                Ok(stream) => break stream,
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    thread::sleep(Duration::from_millis(50))
+                }
+                Err(e) => panic!("{e}"),
+            }
         };
-        v.make(Box::new(io::stdout())).unwrap();
+        stream.write_all(b"ping\n").unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "ping\n");
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
     }
 
     #[test]
-    fn it_works_vsock() {
-        let cpio_path = "my_initrd.cpio";
-        // build initrd
-        {
-            let test_bin_path = TestBinary::relative_to_parent(
-                "vsock-bin",
-                &PathBuf::from_iter(["testbins", "vsock-bin", "Cargo.toml"]),
-            )
-            .with_target("x86_64-unknown-linux-musl")
+    fn it_listens_on_several_vsock_ports() {
+        let cpio_path = "my_ports_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+
+        let vsock_path = "/tmp/test-ports.v.sock";
+        let _ = fs::remove_file(vsock_path);
+        let vsock = VsockConfig::new(vsock_path);
+        assert_eq!(
+            vsock.listener_path(1234),
+            PathBuf::from("/tmp/test-ports.v.sock_1234")
+        );
+        let logs = vsock.listen(1234).unwrap();
+        let control = vsock.listen(1235).unwrap();
+
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- ports")
+            .initrd(File::open(cpio_path).unwrap())
+            .vsock(vsock)
             .build()
             .unwrap();
-            println!("tbp {test_bin_path:?}");
-            let init_bytes = fs::read(test_bin_path).unwrap();
-            let mut outf = File::create(cpio_path).unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+
+        let read_message = |listener: &UnixListener| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = String::new();
+            stream.read_to_string(&mut buf).unwrap();
+            buf
+        };
+        assert_eq!(read_message(&logs), "LOG\n");
+        assert_eq!(read_message(&control), "CONTROL\n");
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+    }
 
-            let cpio_init_entry = NewcBuilder::new("init")
-                .mode(0o777)
-                .set_mode_file_type(newc::ModeFileType::Regular);
-            let mut fp = cpio_init_entry.write(&mut outf, init_bytes.len() as u32);
-            fp.write_all(&init_bytes).unwrap();
-            fp.finish().unwrap();
+    #[test]
+    fn it_moves_bulk_data_over_vsock() {
+        let cpio_path = "my_bulk_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
 
-            newc::trailer(&mut outf).unwrap();
-            outf.flush().unwrap();
-        }
+        let vsock_path = "/tmp/test-bulk.v.sock";
+        let _ = fs::remove_file(vsock_path);
+        let vsock = VsockConfig::new(vsock_path);
+        let listener = vsock.listen(1234).unwrap();
+        let v = Vm::builder()
+            .mem_mib(256)
+            .kernel(PathBuf::from("vmlinux"))
+            .cmdline("quiet panic=-1 reboot=t init=/init -- bulk")
+            .initrd(File::open(cpio_path).unwrap())
+            .vsock(vsock)
+            .build()
+            .unwrap();
+        let handle = v.start(Box::new(io::sink())).unwrap();
+
+        // The guest sends on 4 connections at once; read them concurrently
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (mut stream, _) = listener.accept().unwrap();
+                thread::spawn(move || io::copy(&mut stream, &mut io::sink()).unwrap())
+            })
+            .collect();
+        let received: u64 = readers.into_iter().map(|r| r.join().unwrap()).sum();
+        assert_eq!(received, 4 * 8 * 1024 * 1024);
+        assert_eq!(handle.wait().unwrap(), FcExitCode::Ok);
+    }
 
-        let kernel = File::open("vmlinux").unwrap();
+    #[test]
+    fn it_assigns_guest_cids() {
+        let cpio_path = "my_cid_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+        let vm = |uds_path: &str| {
+            let _ = fs::remove_file(uds_path);
+            Vm::builder()
+                .mem_mib(256)
+                .kernel(PathBuf::from("vmlinux"))
+                .cmdline("quiet panic=-1 reboot=t init=/init -- hang")
+                .initrd(File::open(cpio_path).unwrap())
+                .vsock(VsockConfig::new(uds_path))
+                .build()
+                .unwrap()
+        };
+        let a = vm("/tmp/test-cid-a.v.sock")
+            .start(Box::new(io::sink()))
+            .unwrap();
+        let b = vm("/tmp/test-cid-b.v.sock")
+            .start(Box::new(io::sink()))
+            .unwrap();
+        let (cid_a, cid_b) = (a.guest_cid().unwrap(), b.guest_cid().unwrap());
+        assert!(cid_a >= 3 && cid_b >= 3);
+        assert_ne!(cid_a, cid_b);
+
+        let mut taken = vm("/tmp/test-cid-c.v.sock");
+        taken.vsock.as_mut().unwrap().guest_cid = Some(cid_a);
+        assert!(matches!(
+            taken.start(Box::new(io::sink())),
+            Err(SpawnError::CidInUse { cid }) if cid == cid_a
+        ));
+
+        let mut reserved = vm("/tmp/test-cid-d.v.sock");
+        reserved.vsock.as_mut().unwrap().guest_cid = Some(2);
+        assert!(matches!(
+            reserved.validate(),
+            Err(SpawnError::ReservedCid { cid: 2 })
+        ));
+    }
+
+    #[test]
+    fn it_works_vsock() {
+        let cpio_path = "my_initrd.cpio";
+        build_vsock_bin_initrd(cpio_path);
+
+        let kernel = KernelSource::Path(PathBuf::from("vmlinux"));
         let vsock_path = "/tmp/test.v.sock";
-        let port = 1234;
-        let vsock_listener = format!("{}_{}", vsock_path, port);
         let _ = fs::remove_file(vsock_path);
-        let _ = fs::remove_file(&vsock_listener);
+        let vsock = VsockConfig::new(vsock_path);
+        let listener = vsock.listen(1234).unwrap();
 
         let v = Vm {
             vcpu_count: 1,
             mem_size_mib: 256,
             kernel,
             kernel_cmdline: "quiet panic=-1 reboot=t init=/init".to_string(),
+            cmdline_capacity: None,
+            extra_cmdline_args: vec![],
             rootfs: None,
             initrd: Some(File::open(cpio_path).unwrap()),
             extra_disks: vec![],
             net_config: None,
-            use_hugepages: false,
-            vsock: Some(vsock_path.to_string()),
+            extra_nets: vec![],
+            netns: None,
+            owned_nets: Vec::new(),
+            huge_pages: HugePages::None,
+            allow_overcommit: false,
+            entropy: None,
+            balloon: None,
+            cpu_template: None,
+            smt: false,
+            vcpu_affinity: None,
+            track_dirty_pages: false,
+            mmds: None,
+            instance_id: None,
+            app_name: None,
+            vmm_version: None,
+            serial_banner: false,
+            seccomp: Seccomp::Default,
+            ready_marker: None,
+            console_tail: 0,
+            boot_timer: false,
+            timeout: None,
+            reboot_policy: RebootPolicy::Exit,
+            vsock: Some(vsock),
         };
         let handle = thread::spawn(move || {
-            let listener = UnixListener::bind(vsock_listener).unwrap();
             for stream in listener.incoming() {
                 match stream {
                     Ok(mut stream) => {
@@ -321,10 +4170,9 @@ mod tests {
                 }
             }
         });
-        println!("made vm");
         //v.make(Box::new(io::stdout())).unwrap();
-        v.make(Box::new(io::sink())).unwrap();
-        println!("waiting for thread now");
+        assert_eq!(v.make(Box::new(io::sink())).unwrap(), FcExitCode::Ok);
         handle.join().unwrap();
+        assert!(!Path::new(vsock_path).exists());
     }
 }
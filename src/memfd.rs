@@ -0,0 +1,22 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::fd::FromRawFd;
+
+/// Copies `contents` into an anonymous in-memory file, e.g. to pass an initrd built at
+/// runtime to [`VmBuilder::initrd`](crate::VmBuilder::initrd) without a temporary file.
+///
+/// `name` only shows up in `/proc/self/fd`, for debugging.
+pub fn memfd(name: &str, mut contents: impl Read) -> io::Result<File> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `name` is a valid NUL-terminated string.
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just created and nothing else owns it.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    io::copy(&mut contents, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
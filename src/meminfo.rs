@@ -0,0 +1,37 @@
+use std::fs;
+
+use crate::HugePages;
+
+/// Host memory in MiB that guest memory backed by `huge_pages` can be allocated from right
+/// now, or `None` if the host does not say.
+///
+/// For regular pages this is `MemAvailable` from `/proc/meminfo`; for hugepages, the free
+/// pages of that size in the kernel's hugepage pool.
+pub(crate) fn available_mib(huge_pages: HugePages) -> Option<usize> {
+    match huge_pages.page_size_mib() {
+        None => {
+            let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+            let kib = meminfo.lines().find_map(|line| {
+                line.strip_prefix("MemAvailable:")?
+                    .trim()
+                    .strip_suffix("kB")?
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+            })?;
+            Some(kib / 1024)
+        }
+        Some(page_size_mib) => {
+            let path = format!(
+                "/sys/kernel/mm/hugepages/hugepages-{}kB/free_hugepages",
+                page_size_mib * 1024
+            );
+            // No pool for this page size means no pages of it can be allocated
+            let free: usize = match fs::read_to_string(path) {
+                Ok(free) => free.trim().parse().ok()?,
+                Err(_) => 0,
+            };
+            Some(free * page_size_mib)
+        }
+    }
+}
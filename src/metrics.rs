@@ -0,0 +1,27 @@
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use vmm::logger::{FcLineWriter, METRICS};
+
+use crate::SpawnError;
+
+/// Sends firecracker's metrics to `dest` as one JSON object per line, every `period`.
+///
+/// Firecracker keeps its metrics in a process-wide static, so this can only be called once
+/// per process and the counters are shared by every VM it runs. Each flush reports the
+/// increments since the previous one.
+pub fn init_metrics(dest: Box<dyn Write + Send>, period: Duration) -> Result<(), SpawnError> {
+    METRICS
+        .init(FcLineWriter::new(dest))
+        .map_err(SpawnError::Metrics)?;
+    thread::Builder::new()
+        .name("fc_metrics".to_string())
+        .spawn(move || loop {
+            thread::sleep(period);
+            // A failed flush is dropped, like firecracker itself does
+            let _ = METRICS.write();
+        })
+        .map_err(SpawnError::Thread)?;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+/// Moves the calling thread into a mount namespace of its own and bind-mounts `dir` over
+/// `target` there, so paths under `target` resolve to `dir` for this thread and the threads
+/// it starts, and are untouched for everyone else. Needs `CAP_SYS_ADMIN`.
+pub(crate) fn bind_private(dir: &Path, target: &Path) -> io::Result<()> {
+    let dir = c_path(dir)?;
+    let target = c_path(target)?;
+    let root = c_path(Path::new("/"))?;
+    // SAFETY: unshare has no preconditions; it only affects this thread.
+    if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Keeps the bind mount from propagating back to the host's namespace
+    // SAFETY: all pointers are null or valid NUL-terminated strings.
+    if unsafe {
+        libc::mount(
+            ptr::null(),
+            root.as_ptr(),
+            ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            ptr::null(),
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: as above.
+    if unsafe {
+        libc::mount(
+            dir.as_ptr(),
+            target.as_ptr(),
+            ptr::null(),
+            libc::MS_BIND,
+            ptr::null(),
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
@@ -0,0 +1,77 @@
+use std::fs::File;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::Arc;
+use std::{panic, thread};
+
+use crate::Veth;
+
+/// A network namespace to run VMs in, see [`VmBuilder::netns`](crate::VmBuilder::netns), so
+/// that each has a network stack of its own, like under firecracker's jailer.
+///
+/// Namespaces are per thread: the VM's threads are started inside it, while the calling
+/// thread stays where it is. Create TAP interfaces inside it with [`NetNs::run`], and drop
+/// them there too. Connect the namespace to the host with [`NetNs::veth`].
+#[derive(Clone, Debug)]
+pub struct NetNs(Arc<OwnedFd>);
+
+impl NetNs {
+    /// Creates a new network namespace, with only a loopback interface. It lives as long as
+    /// this `NetNs`, its clones, and the VMs started in it. Needs `CAP_SYS_ADMIN`.
+    pub fn create() -> io::Result<NetNs> {
+        thread::spawn(|| {
+            // SAFETY: unshare has no preconditions; it only affects this thread.
+            if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            NetNs::open("/proc/thread-self/ns/net")
+        })
+        .join()
+        .unwrap_or_else(|e| panic::resume_unwind(e))
+    }
+
+    /// Opens an existing namespace, e.g. `/run/netns/<name>` as created by `ip netns add`
+    pub fn open(path: impl AsRef<Path>) -> io::Result<NetNs> {
+        Ok(NetNs(Arc::new(File::open(path)?.into())))
+    }
+
+    /// Runs `f` on a new thread inside the namespace and returns its result, e.g.
+    /// `ns.run(|| Tap::create("tap0", None))`. Threads `f` starts are inside it too. Needs
+    /// `CAP_SYS_ADMIN`.
+    pub fn run<T: Send>(&self, f: impl FnOnce() -> T + Send) -> io::Result<T> {
+        thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    // SAFETY: the fd is an open namespace file; setns only affects this
+                    // thread.
+                    if unsafe { libc::setns(self.0.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(f())
+                })
+                .join()
+                .unwrap_or_else(|e| panic::resume_unwind(e))
+        })
+    }
+
+    /// Connects the namespace to the host with a veth pair, `host_name` on the host and
+    /// `ns_name` inside the namespace, on the subnet `subnet/prefix_len`.
+    ///
+    /// Addressed like [`HostNetwork::nat`](crate::HostNetwork::nat): the host end takes the
+    /// subnet's first address, the namespace's default gateway, and the namespace end the
+    /// second. Traffic from the subnet is masqueraded behind the host's addresses, and IP
+    /// forwarding is enabled host-wide while the pair exists. For a guest in the namespace to
+    /// get out, give it NAT inside the namespace too, e.g. `ns.run(|| HostNetwork::nat(..))`.
+    /// Needs the `ip` and `nft` commands.
+    pub fn veth(
+        &self,
+        host_name: &str,
+        ns_name: &str,
+        subnet: Ipv4Addr,
+        prefix_len: u8,
+    ) -> io::Result<Veth> {
+        Veth::create(self, host_name, ns_name, subnet, prefix_len)
+    }
+}
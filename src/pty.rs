@@ -0,0 +1,71 @@
+use std::ffi::{CStr, OsStr};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Serial output to a pseudo-terminal, for attaching to the console with `screen` or
+/// `minicom`, e.g. `screen /dev/pts/3`, see [`PtySerialOut::path`].
+///
+/// The terminal is in raw mode, so the guest's output reaches it unchanged. Output is
+/// dropped while nothing reads the terminal and its buffer is full, rather than stalling the
+/// guest. The console is output only: what is typed into the terminal does not reach the
+/// guest.
+pub struct PtySerialOut {
+    master: File,
+    path: PathBuf,
+}
+
+impl PtySerialOut {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: posix_openpt has no preconditions.
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it.
+        let master = unsafe { File::from_raw_fd(fd) };
+        let fd = master.as_raw_fd();
+        let mut name = [0 as libc::c_char; 64];
+        // SAFETY: `fd` is a pty master; `name` and `termios` outlive the calls that fill them,
+        // and ptsname_r NUL-terminates `name` on success.
+        let path = unsafe {
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ret = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            PathBuf::from(OsStr::from_bytes(CStr::from_ptr(name.as_ptr()).to_bytes()))
+        };
+        Ok(PtySerialOut { master, path })
+    }
+
+    /// The terminal to attach to, e.g. `/dev/pts/3`
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Write for PtySerialOut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.master.write(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
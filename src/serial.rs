@@ -0,0 +1,559 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use regex::Regex;
+
+use crate::SerialOut;
+
+/// The last line the kernel prints about a panic, before it hangs or reboots per `panic=`
+const PANIC_END: &str = "---[ end Kernel panic";
+
+/// Starts the line with the panic message
+const PANIC_START: &str = "Kernel panic - not syncing: ";
+
+/// Lines kept between the start and the end of a panic report, enough for a call trace
+const MAX_PANIC_LINES: usize = 128;
+
+/// Longest line the watchers buffer while waiting for its newline; the rest is dropped, so a
+/// guest that never writes a newline cannot make the host buffer all its output
+const MAX_LINE_LEN: usize = 4096;
+
+/// Records when the guest wrote the ready marker to its serial console
+#[derive(Default)]
+pub(crate) struct Ready {
+    seen_at: Mutex<Option<Instant>>,
+    cond: Condvar,
+}
+
+impl Ready {
+    fn set(&self) {
+        *self.seen_at.lock().unwrap() = Some(Instant::now());
+        self.cond.notify_all();
+    }
+
+    /// Blocks for up to `timeout` until the marker is seen, returning when it was.
+    pub(crate) fn wait(&self, timeout: Duration) -> Option<Instant> {
+        let seen_at = self.seen_at.lock().unwrap();
+        let (seen_at, _) = self
+            .cond
+            .wait_timeout_while(seen_at, timeout, |seen_at| seen_at.is_none())
+            .unwrap();
+        *seen_at
+    }
+}
+
+/// Forwards serial output to `inner`, flagging `ready` the first time `marker` goes by.
+pub(crate) struct ReadyWatcher {
+    inner: Box<dyn SerialOut>,
+    marker: Vec<u8>,
+    /// Trailing bytes of previous writes, in case the marker is split across writes
+    tail: Vec<u8>,
+    ready: Arc<Ready>,
+    seen: bool,
+}
+
+impl ReadyWatcher {
+    pub(crate) fn new(inner: Box<dyn SerialOut>, marker: &str, ready: Arc<Ready>) -> Self {
+        let seen = marker.is_empty();
+        if seen {
+            ready.set();
+        }
+        ReadyWatcher {
+            inner,
+            marker: marker.as_bytes().to_vec(),
+            tail: Vec::new(),
+            ready,
+            seen,
+        }
+    }
+}
+
+impl Write for ReadyWatcher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if !self.seen {
+            self.tail.extend_from_slice(&buf[..n]);
+            if self
+                .tail
+                .windows(self.marker.len())
+                .any(|w| w == self.marker)
+            {
+                self.seen = true;
+                self.tail = Vec::new();
+                self.ready.set();
+            } else {
+                let keep = self.marker.len() - 1;
+                self.tail.drain(..self.tail.len().saturating_sub(keep));
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Callers of [`VmHandle::wait_for_serial`] waiting for a console line to match their
+/// pattern
+///
+/// [`VmHandle::wait_for_serial`]: crate::VmHandle::wait_for_serial
+#[derive(Default)]
+pub(crate) struct SerialWaiters {
+    waiting: Mutex<Vec<SerialWaiter>>,
+    next_id: AtomicU64,
+    cond: Condvar,
+}
+
+struct SerialWaiter {
+    id: u64,
+    pattern: Regex,
+    matched: Option<String>,
+}
+
+impl SerialWaiters {
+    /// Blocks for up to `timeout` until a line written from now on matches `pattern`,
+    /// returning that line.
+    pub(crate) fn wait(&self, pattern: &Regex, timeout: Duration) -> Option<String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting.push(SerialWaiter {
+            id,
+            pattern: pattern.clone(),
+            matched: None,
+        });
+        let (mut waiting, _) = self
+            .cond
+            .wait_timeout_while(waiting, timeout, |waiting| {
+                waiting
+                    .iter()
+                    .any(|waiter| waiter.id == id && waiter.matched.is_none())
+            })
+            .unwrap();
+        let index = waiting.iter().position(|waiter| waiter.id == id).unwrap();
+        waiting.swap_remove(index).matched
+    }
+
+    fn is_waiting(&self) -> bool {
+        !self.waiting.lock().unwrap().is_empty()
+    }
+
+    fn check(&self, line: &str) {
+        let mut waiting = self.waiting.lock().unwrap();
+        let mut matched = false;
+        for waiter in waiting.iter_mut().filter(|waiter| waiter.matched.is_none()) {
+            if waiter.pattern.is_match(line) {
+                waiter.matched = Some(line.to_string());
+                matched = true;
+            }
+        }
+        if matched {
+            self.cond.notify_all();
+        }
+    }
+}
+
+/// Forwards serial output to `inner`, matching each line against the patterns of `waiters`.
+/// The line being written is matched too, so prompts such as `login: ` that wait for input
+/// on the same line are seen.
+pub(crate) struct SerialMatcher {
+    inner: Box<dyn SerialOut>,
+    line: Vec<u8>,
+    waiters: Arc<SerialWaiters>,
+}
+
+impl SerialMatcher {
+    pub(crate) fn new(inner: Box<dyn SerialOut>, waiters: Arc<SerialWaiters>) -> Self {
+        SerialMatcher {
+            inner,
+            line: Vec::new(),
+            waiters,
+        }
+    }
+
+    fn check_line(&self) {
+        let line = String::from_utf8_lossy(&self.line);
+        self.waiters.check(line.trim_end());
+    }
+}
+
+impl Write for SerialMatcher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let waiting = self.waiters.is_waiting();
+        for &b in &buf[..n] {
+            if b == b'\n' {
+                if waiting {
+                    self.check_line();
+                }
+                self.line.clear();
+            } else if self.line.len() < MAX_LINE_LEN {
+                self.line.push(b);
+            }
+        }
+        if waiting && !self.line.is_empty() {
+            self.check_line();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// What the guest kernel printed when it panicked, see [`PanicWatcher`]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PanicReport {
+    pub(crate) message: String,
+    /// Lines printed between the message and the end of the report, e.g. the call trace
+    pub(crate) backtrace_lines: Vec<String>,
+}
+
+/// Set once the guest kernel has panicked
+#[derive(Default)]
+pub(crate) struct GuestPanic(Mutex<Option<PanicReport>>);
+
+impl GuestPanic {
+    pub(crate) fn seen(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn report(&self) -> Option<PanicReport> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Forwards serial output to `inner`, recording a kernel panic report in `panic` once its
+/// last line goes by.
+pub(crate) struct PanicWatcher {
+    inner: Box<dyn SerialOut>,
+    line: Vec<u8>,
+    /// The report being read, from the panic message on
+    partial: Option<PanicReport>,
+    panic: Arc<GuestPanic>,
+}
+
+impl PanicWatcher {
+    pub(crate) fn new(inner: Box<dyn SerialOut>, panic: Arc<GuestPanic>) -> Self {
+        PanicWatcher {
+            inner,
+            line: Vec::new(),
+            partial: None,
+            panic,
+        }
+    }
+
+    fn read_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line);
+        let line = line.trim_end();
+        if line.contains(PANIC_END) {
+            let report = self.partial.take().unwrap_or_default();
+            *self.panic.0.lock().unwrap() = Some(report);
+        } else if let Some(partial) = &mut self.partial {
+            if partial.backtrace_lines.len() < MAX_PANIC_LINES {
+                partial.backtrace_lines.push(line.to_string());
+            }
+        } else if let Some((_, message)) = line.split_once(PANIC_START) {
+            self.partial = Some(PanicReport {
+                message: message.to_string(),
+                backtrace_lines: Vec::new(),
+            });
+        }
+        self.line.clear();
+    }
+}
+
+impl Write for PanicWatcher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if !self.panic.seen() {
+            for &b in &buf[..n] {
+                if b == b'\n' {
+                    self.read_line();
+                } else if self.line.len() < MAX_LINE_LEN {
+                    self.line.push(b);
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The last lines the guest wrote to its console, see [`VmHandle::console_tail`]
+///
+/// [`VmHandle::console_tail`]: crate::VmHandle::console_tail
+pub(crate) struct ConsoleTail {
+    lines: Mutex<VecDeque<String>>,
+    capacity: usize,
+}
+
+impl ConsoleTail {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ConsoleTail {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    pub(crate) fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Forwards serial output to `inner`, keeping its last lines in `tail`.
+pub(crate) struct TailWatcher {
+    inner: Box<dyn SerialOut>,
+    line: Vec<u8>,
+    tail: Arc<ConsoleTail>,
+}
+
+impl TailWatcher {
+    pub(crate) fn new(inner: Box<dyn SerialOut>, tail: Arc<ConsoleTail>) -> Self {
+        TailWatcher {
+            inner,
+            line: Vec::new(),
+            tail,
+        }
+    }
+}
+
+impl Write for TailWatcher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        for &b in &buf[..n] {
+            if b == b'\n' {
+                let line = String::from_utf8_lossy(&self.line);
+                self.tail.push(line.trim_end().to_string());
+                self.line.clear();
+            } else if self.line.len() < MAX_LINE_LEN {
+                self.line.push(b);
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Lets consecutive boots of a VM write to the same sink
+#[derive(Clone)]
+pub(crate) struct SharedSerialOut(Arc<Mutex<Box<dyn SerialOut>>>);
+
+impl SharedSerialOut {
+    pub(crate) fn new(inner: Box<dyn SerialOut>) -> Self {
+        SharedSerialOut(Arc::new(Mutex::new(inner)))
+    }
+}
+
+impl Write for SharedSerialOut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Fans serial output out to several sinks, e.g. the terminal and a log file. Each sink gets
+/// the full byte stream.
+///
+/// A sink that fails misses the output it failed on, and a warning is logged when it starts
+/// failing; the others carry on. Writes only fail when every sink failed, so the serial
+/// device's retry never hands a sink the same bytes twice.
+pub struct TeeSerialOut {
+    sinks: Vec<Box<dyn SerialOut>>,
+    /// Whether each sink failed its last write, so its failures are logged once
+    failing: Vec<bool>,
+}
+
+impl TeeSerialOut {
+    pub fn new(sinks: Vec<Box<dyn SerialOut>>) -> Self {
+        let failing = vec![false; sinks.len()];
+        TeeSerialOut { sinks, failing }
+    }
+}
+
+impl Write for TeeSerialOut {
+    /// Writes `buf` to every sink, even if an earlier one fails. Fails with the first error
+    /// only if no sink took `buf`.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut error = None;
+        let mut taken = false;
+        for (i, (sink, failing)) in self.sinks.iter_mut().zip(&mut self.failing).enumerate() {
+            match sink.write_all(buf) {
+                Ok(()) => {
+                    taken = true;
+                    *failing = false;
+                }
+                Err(e) => {
+                    if !std::mem::replace(failing, true) {
+                        warn!("serial sink {i} failed, skipping it until it recovers: {e}");
+                    }
+                    error = error.or(Some(e));
+                }
+            }
+        }
+        match error {
+            Some(e) if !taken => Err(e),
+            _ => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.flush() {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+}
+
+/// Forwards serial output one whole line at a time, each starting with `prefix`, so the
+/// output of several VMs sharing a sink does not interleave mid-line.
+///
+/// A trailing partial line is forwarded when the adapter is dropped.
+pub struct PrefixSerialOut {
+    inner: Box<dyn SerialOut>,
+    prefix: Vec<u8>,
+    line: Vec<u8>,
+    /// How much of `line` `inner` already took, if writing it failed midway
+    written: usize,
+}
+
+impl PrefixSerialOut {
+    pub fn new(prefix: impl Into<String>, inner: Box<dyn SerialOut>) -> Self {
+        let prefix = prefix.into().into_bytes();
+        PrefixSerialOut {
+            line: prefix.clone(),
+            inner,
+            prefix,
+            written: 0,
+        }
+    }
+
+    /// Writes the rest of `line` to `inner`, from where a failed attempt left off
+    fn write_line(&mut self) -> io::Result<()> {
+        while self.written < self.line.len() {
+            match self.inner.write(&self.line[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for PrefixSerialOut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for (i, &b) in buf.iter().enumerate() {
+            self.line.push(b);
+            if b == b'\n' {
+                if let Err(e) = self.write_line() {
+                    // Leave the newline, which `inner` cannot have taken yet, to the retry; it
+                    // resumes the line where `inner` stopped. The bytes before it are taken
+                    self.line.pop();
+                    return if i == 0 { Err(e) } else { Ok(i) };
+                }
+                self.line.truncate(0);
+                self.line.extend_from_slice(&self.prefix);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Flushes `inner` only; the serial device flushes after every byte, so a partial line
+    /// is held back until it is complete.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Drop for PrefixSerialOut {
+    fn drop(&mut self) {
+        if self.line.len() > self.prefix.len() {
+            self.line.push(b'\n');
+            let _ = self.write_line();
+            let _ = self.inner.flush();
+        }
+    }
+}
+
+/// Calls `callback` with each line of serial output, e.g. to parse the guest's boot log.
+///
+/// Lines are passed without their line ending (the guest's `\r\n` or `\n`); bytes that are
+/// not UTF-8 are replaced with U+FFFD. A trailing partial line is passed when the adapter is
+/// dropped.
+pub struct LineSerialOut {
+    callback: Box<dyn FnMut(Instant, &str) + Send>,
+    line: Vec<u8>,
+}
+
+impl LineSerialOut {
+    pub fn new(mut callback: impl FnMut(&str) + Send + 'static) -> Self {
+        Self::timestamped(move |_, line| callback(line))
+    }
+
+    /// Like [`LineSerialOut::new`], but also passes when the line was completed
+    pub fn timestamped(callback: impl FnMut(Instant, &str) + Send + 'static) -> Self {
+        LineSerialOut {
+            callback: Box::new(callback),
+            line: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self) {
+        let line = self.line.strip_suffix(b"\n").unwrap_or(&self.line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        (self.callback)(Instant::now(), &String::from_utf8_lossy(line));
+        self.line.clear();
+    }
+}
+
+impl Write for LineSerialOut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &b in buf {
+            self.line.push(b);
+            if b == b'\n' {
+                self.emit();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LineSerialOut {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.emit();
+        }
+    }
+}
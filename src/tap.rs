@@ -0,0 +1,309 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+use log::warn;
+
+use crate::SpawnError;
+
+/// `_IOW('T', 202, int)`
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+/// `_IOW('T', 203, int)`
+const TUNSETPERSIST: libc::c_ulong = 0x4004_54cb;
+/// `_IOW('T', 204, int)`
+const TUNSETOWNER: libc::c_ulong = 0x4004_54cc;
+
+/// Not in libc; see `linux/sockios.h`
+const SIOCBRADDIF: libc::c_ulong = 0x89a2;
+
+/// The flags firecracker opens its TAP devices with
+const TAP_FLAGS: libc::c_int = libc::IFF_TAP | libc::IFF_NO_PI | libc::IFF_VNET_HDR;
+
+/// `struct ifreq` with only the members TUNSETIFF and SIOC[GS]IFFLAGS use
+#[repr(C)]
+struct IfReq {
+    ifr_name: [libc::c_char; libc::IFNAMSIZ],
+    ifr_flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// `struct ifreq` with the address member SIOCSIFADDR and SIOCSIFNETMASK use
+#[repr(C)]
+struct IfReqAddr {
+    ifr_name: [libc::c_char; libc::IFNAMSIZ],
+    ifr_addr: libc::sockaddr_in,
+    _pad: [u8; 8],
+}
+
+/// `struct ifreq` with the interface index member SIOCBRADDIF uses
+#[repr(C)]
+struct IfReqIndex {
+    ifr_name: [libc::c_char; libc::IFNAMSIZ],
+    ifr_ifindex: libc::c_int,
+    _pad: [u8; 20],
+}
+
+/// `struct in6_ifreq`, which SIOCSIFADDR takes on an IPv6 socket
+#[repr(C)]
+struct In6IfReq {
+    ifr6_addr: libc::in6_addr,
+    ifr6_prefixlen: u32,
+    ifr6_ifindex: libc::c_int,
+}
+
+fn ifr_name(name: &str) -> [libc::c_char; libc::IFNAMSIZ] {
+    let mut ifr_name = [0; libc::IFNAMSIZ];
+    for (dst, src) in ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    ifr_name
+}
+
+/// A TAP interface created by this process, for [`NetConfig::tap_iface_name`], so that no
+/// `ip tuntap add` is needed beforehand. Creating one needs `CAP_NET_ADMIN`.
+///
+/// The interface is deleted when the `Tap` is dropped, which must happen after the VM using
+/// it has stopped: the interface cannot be deleted while firecracker is attached to it.
+/// [`VmBuilder::own_tap`](crate::VmBuilder::own_tap) leaves that to the VM's handle.
+///
+/// [`NetConfig::tap_iface_name`]: crate::NetConfig::tap_iface_name
+#[derive(Debug)]
+pub struct Tap {
+    name: String,
+}
+
+impl Tap {
+    /// Creates the interface `name` and brings it up. With an `owner`, that user can attach
+    /// to it without `CAP_NET_ADMIN`, e.g. to run the VM unprivileged.
+    pub fn create(name: &str, owner: Option<libc::uid_t>) -> io::Result<Tap> {
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid interface name {name:?}"),
+            ));
+        }
+        if Path::new("/sys/class/net").join(name).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("interface {name} already exists"),
+            ));
+        }
+        let tun = attach(name)?;
+        if let Some(uid) = owner {
+            tun_ioctl(&tun, TUNSETOWNER, libc::c_ulong::from(uid))?;
+        }
+        // Keeps the interface once `tun` is closed, so firecracker can attach to it
+        tun_ioctl(&tun, TUNSETPERSIST, 1)?;
+        let tap = Tap {
+            name: name.to_string(),
+        };
+        drop(tun);
+        let sock = inet_socket(libc::AF_INET)?;
+        let mut req = IfReq {
+            ifr_name: ifr_name(name),
+            ifr_flags: 0,
+            _pad: [0; 22],
+        };
+        // SAFETY: `req` is a valid `ifreq` that outlives both calls.
+        unsafe {
+            if libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFFLAGS as _, &mut req) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            req.ifr_flags |= libc::IFF_UP as libc::c_short;
+            if libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFFLAGS as _, &req) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(tap)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Assigns `addr/prefix_len` to the host side of the interface, e.g. the guest's gateway
+    pub fn set_address(&self, addr: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+        if prefix_len > 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid prefix length {prefix_len}"),
+            ));
+        }
+        let netmask = u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0);
+        let sock = inet_socket(libc::AF_INET)?;
+        for (request, addr) in [
+            (libc::SIOCSIFADDR, addr),
+            (libc::SIOCSIFNETMASK, Ipv4Addr::from(netmask)),
+        ] {
+            let req = IfReqAddr {
+                ifr_name: ifr_name(&self.name),
+                ifr_addr: libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: 0,
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from(addr).to_be(),
+                    },
+                    sin_zero: [0; 8],
+                },
+                _pad: [0; 8],
+            };
+            // SAFETY: `req` is a valid `ifreq` that outlives the call.
+            if unsafe { libc::ioctl(sock.as_raw_fd(), request as _, &req) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the interface to the existing bridge `bridge`, putting the guest on the bridge's
+    /// network. It leaves the bridge when it is deleted.
+    pub fn add_to_bridge(&self, bridge: &str) -> io::Result<()> {
+        if bridge.is_empty() || bridge.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid bridge name {bridge:?}"),
+            ));
+        }
+        let req = IfReqIndex {
+            ifr_name: ifr_name(bridge),
+            ifr_ifindex: self.index()?,
+            _pad: [0; 20],
+        };
+        let sock = inet_socket(libc::AF_INET)?;
+        // SAFETY: `req` is a valid `ifreq` that outlives the call.
+        if unsafe { libc::ioctl(sock.as_raw_fd(), SIOCBRADDIF as _, &req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Assigns `addr/prefix_len` to the host side of the interface, next to its IPv4 address
+    /// and link-local address
+    pub fn set_address6(&self, addr: Ipv6Addr, prefix_len: u8) -> io::Result<()> {
+        if prefix_len > 128 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid prefix length {prefix_len}"),
+            ));
+        }
+        let req = In6IfReq {
+            ifr6_addr: libc::in6_addr {
+                s6_addr: addr.octets(),
+            },
+            ifr6_prefixlen: u32::from(prefix_len),
+            ifr6_ifindex: self.index()?,
+        };
+        let sock = inet_socket(libc::AF_INET6)?;
+        // SAFETY: `req` is a valid `in6_ifreq` that outlives the call.
+        if unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFADDR as _, &req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn index(&self) -> io::Result<libc::c_int> {
+        let name = CString::new(self.name.as_str())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: `name` is a valid NUL-terminated string.
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(index as libc::c_int)
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        let delete = || -> io::Result<()> {
+            let tun = attach(&self.name)?;
+            tun_ioctl(&tun, TUNSETPERSIST, 0)?;
+            // The interface goes away once `tun` is closed
+            Ok(())
+        };
+        if let Err(e) = delete() {
+            warn!("failed to delete TAP interface {}: {e}", self.name);
+        }
+    }
+}
+
+/// Opens the TAP interface `name`, creating it if it does not exist
+fn attach(name: &str) -> io::Result<File> {
+    let tun = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")?;
+    let mut req = IfReq {
+        ifr_name: ifr_name(name),
+        ifr_flags: TAP_FLAGS as libc::c_short,
+        _pad: [0; 22],
+    };
+    // SAFETY: `req` is a valid `ifreq` that outlives the call.
+    if unsafe { libc::ioctl(tun.as_raw_fd(), TUNSETIFF as _, &mut req) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(tun)
+}
+
+/// Runs one of the TUNSET* ioctls that take an integer argument
+fn tun_ioctl(tun: &File, request: libc::c_ulong, arg: libc::c_ulong) -> io::Result<()> {
+    // SAFETY: `request` takes a plain integer argument.
+    if unsafe { libc::ioctl(tun.as_raw_fd(), request as _, arg) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A socket of address family `domain` to configure interfaces through
+fn inet_socket(domain: libc::c_int) -> io::Result<File> {
+    // SAFETY: socket has no preconditions.
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just created and nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Checks that `name` is a TAP interface that no other process is attached to.
+pub(crate) fn check(name: &str) -> Result<(), SpawnError> {
+    let not_found = || SpawnError::TapNotFound {
+        name: name.to_string(),
+    };
+    // Only TUN/TAP devices have `tun_flags`
+    let flags = std::fs::read_to_string(Path::new("/sys/class/net").join(name).join("tun_flags"))
+        .map_err(|_| not_found())?;
+    let flags =
+        i32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).map_err(|_| not_found())?;
+    if flags & libc::IFF_TAP == 0 || name.len() >= libc::IFNAMSIZ {
+        return Err(not_found());
+    }
+
+    // Attaching the way firecracker does fails with EBUSY if someone else holds the queue.
+    // Other failures (e.g. missing permissions) are left for firecracker to report.
+    let Ok(tun) = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")
+    else {
+        return Ok(());
+    };
+    let mut req = IfReq {
+        ifr_name: ifr_name(name),
+        ifr_flags: TAP_FLAGS as libc::c_short,
+        _pad: [0; 22],
+    };
+    // SAFETY: `req` is a valid `ifreq` that outlives the call.
+    let ret = unsafe { libc::ioctl(tun.as_raw_fd(), TUNSETIFF as _, &mut req) };
+    if ret < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EBUSY) {
+        return Err(SpawnError::TapBusy {
+            name: name.to_string(),
+        });
+    }
+    Ok(())
+}
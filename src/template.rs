@@ -0,0 +1,46 @@
+use std::path::{Path, PathBuf};
+
+use crate::{RestoreConfig, SpawnError, Vm, VmHandle};
+
+/// A full snapshot of a VM taken once it was ready, which any number of clones are restored
+/// from, see [`Vm::clone_for_fork`].
+///
+/// Clones map the memory file privately and share its pages until they write to them. The
+/// snapshot files are left in place when the template is dropped.
+///
+/// Firecracker restores devices exactly as they were snapshotted, so every clone gets the
+/// template's TAP device names and vsock socket path, and the guest keeps the MAC addresses
+/// and vsock CID it had. Neither can be changed on restore: the guest driver reads its MAC
+/// once at probe time, and firecracker's vsock is routed through the host socket, never by
+/// CID. Give each clone of a template with network interfaces a network namespace, see
+/// [`RestoreConfig::netns`], so identical TAP names and MACs are on separate links, and a
+/// vsock directory, see [`RestoreConfig::vsock_dir`].
+#[derive(Clone, Debug)]
+pub struct Template {
+    mem_path: PathBuf,
+    state_path: PathBuf,
+}
+
+impl Template {
+    pub(crate) fn new(mem_path: PathBuf, state_path: PathBuf) -> Self {
+        Template {
+            mem_path,
+            state_path,
+        }
+    }
+
+    pub fn mem_path(&self) -> &Path {
+        &self.mem_path
+    }
+
+    pub fn state_path(&self) -> &Path {
+        &self.state_path
+    }
+
+    /// Restores and resumes a clone. `config` gives it its own instance id, timeout, network
+    /// namespace and vsock socket, and swaps in per-clone disks through `disk_paths`, e.g. a
+    /// fresh copy of a writable disk, as clones must not write to the same file.
+    pub fn spawn(&self, config: RestoreConfig) -> Result<VmHandle, SpawnError> {
+        Vm::from_snapshot(&self.mem_path, &self.state_path, config)
+    }
+}
@@ -0,0 +1,139 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::process::{self, Command};
+
+use log::warn;
+
+use crate::hostnet::{masquerade, nft, Forwarding, IPV4_FORWARD};
+use crate::NetNs;
+
+/// A veth pair connecting a [`NetNs`] to the host, see [`NetNs::veth`]. Needs
+/// `CAP_NET_ADMIN`.
+///
+/// Both ends, and the masquerade rule, are removed on drop, and IP forwarding is set back
+/// once nothing else needs it.
+#[derive(Debug)]
+pub struct Veth {
+    host_name: String,
+    nat_table: Option<String>,
+    gateway: Ipv4Addr,
+    address: Ipv4Addr,
+    /// Dropped after the masquerade rule is deleted
+    forwarding: Option<Forwarding>,
+}
+
+impl Veth {
+    pub(crate) fn create(
+        netns: &NetNs,
+        host_name: &str,
+        ns_name: &str,
+        subnet: Ipv4Addr,
+        prefix_len: u8,
+    ) -> io::Result<Veth> {
+        if prefix_len > 30 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("subnet /{prefix_len} has no room for a host and a namespace"),
+            ));
+        }
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0);
+        let network = Ipv4Addr::from(u32::from(subnet) & mask);
+        let gateway = Ipv4Addr::from(u32::from(network) + 1);
+        let address = Ipv4Addr::from(u32::from(network) + 2);
+        // Created from inside the namespace, with the host end moved to the namespace of this
+        // process's main thread, as the namespace has no name or pid to point `ip` at
+        netns.run(|| {
+            ip(&[
+                "link",
+                "add",
+                ns_name,
+                "type",
+                "veth",
+                "peer",
+                "name",
+                host_name,
+                "netns",
+                &process::id().to_string(),
+            ])
+        })??;
+        // Deletes both ends if the rest fails
+        let mut veth = Veth {
+            host_name: host_name.to_string(),
+            nat_table: None,
+            gateway,
+            address,
+            forwarding: None,
+        };
+        ip(&[
+            "addr",
+            "add",
+            &format!("{gateway}/{prefix_len}"),
+            "dev",
+            host_name,
+        ])?;
+        ip(&["link", "set", host_name, "up"])?;
+        netns.run(|| {
+            ip(&[
+                "addr",
+                "add",
+                &format!("{address}/{prefix_len}"),
+                "dev",
+                ns_name,
+            ])?;
+            ip(&["link", "set", ns_name, "up"])?;
+            ip(&["link", "set", "lo", "up"])?;
+            ip(&["route", "add", "default", "via", &gateway.to_string()])
+        })??;
+        veth.forwarding = Some(Forwarding::enable(IPV4_FORWARD)?);
+        veth.nat_table = Some(masquerade(
+            "ip",
+            &format!("{network}/{prefix_len}"),
+            host_name,
+        )?);
+        Ok(veth)
+    }
+
+    /// The name of the host end
+    pub fn host_name(&self) -> &str {
+        &self.host_name
+    }
+
+    /// The host end's address, the namespace's default gateway
+    pub fn gateway(&self) -> Ipv4Addr {
+        self.gateway
+    }
+
+    /// The namespace end's address
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+}
+
+impl Drop for Veth {
+    fn drop(&mut self) {
+        if let Some(table) = &self.nat_table {
+            if let Err(e) = nft(&format!("delete table {table}")) {
+                warn!("failed to delete nftables table {table}: {e}");
+            }
+        }
+        // Deleting one end deletes the pair
+        if let Err(e) = ip(&["link", "del", &self.host_name]) {
+            warn!("failed to delete veth {}: {e}", self.host_name);
+        }
+    }
+}
+
+/// Runs `ip` with `args`
+fn ip(args: &[&str]) -> io::Result<()> {
+    let output = Command::new("ip").args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
@@ -1,8 +1,238 @@
-use std::io::Write;
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::TcpStream;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use vsock::{VsockListener, VsockStream, VMADDR_CID_ANY, VMADDR_CID_HOST};
+
+static GOT_CTRL_ALT_DEL: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    GOT_CTRL_ALT_DEL.store(true, Ordering::SeqCst);
+}
+
+fn send(port: u32, buf: &[u8]) {
+    let mut s = VsockStream::connect_with_cid_port(VMADDR_CID_HOST, port).unwrap();
+    s.write_all(buf).unwrap();
+}
+
+/// Returns the error of a libc call that failed
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Brings `iface` up with `addr`, then sends an HTTP request to MMDS through that interface
+/// only and returns the start of the reply
+fn mmds_get(iface: &str, addr: [u8; 4]) -> io::Result<Vec<u8>> {
+    #[repr(C)]
+    struct IfReq {
+        name: [u8; libc::IFNAMSIZ],
+        data: [u8; 24],
+    }
+    let mut req = IfReq {
+        name: [0; libc::IFNAMSIZ],
+        data: [0; 24],
+    };
+    req.name[..iface.len()].copy_from_slice(iface.as_bytes());
+    let timeout = libc::timeval {
+        tv_sec: 5,
+        tv_usec: 0,
+    };
+    let mmds = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 80u16.to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from_ne_bytes([169, 254, 169, 254]),
+        },
+        sin_zero: [0; 8],
+    };
+    unsafe {
+        let fd = check(libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0))?;
+        let sock = OwnedFd::from_raw_fd(fd);
+        // A sockaddr_in; the kernel gives the link-local address its classful /16
+        req.data[..2].copy_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+        req.data[4..8].copy_from_slice(&addr);
+        check(libc::ioctl(fd, libc::SIOCSIFADDR, &req))?;
+        req.data = [0; 24];
+        req.data[..2].copy_from_slice(&(libc::IFF_UP as u16).to_ne_bytes());
+        check(libc::ioctl(fd, libc::SIOCSIFFLAGS, &req))?;
+        check(libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            iface.as_ptr().cast(),
+            iface.len() as libc::socklen_t,
+        ))?;
+        for option in [libc::SO_SNDTIMEO, libc::SO_RCVTIMEO] {
+            check(libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                (&timeout as *const libc::timeval).cast(),
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            ))?;
+        }
+        check(libc::connect(
+            fd,
+            (&mmds as *const libc::sockaddr_in).cast(),
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        ))?;
+        let mut stream = TcpStream::from(sock);
+        stream.write_all(b"GET / HTTP/1.1\r\nAccept: application/json\r\n\r\n")?;
+        let mut reply = vec![0; 16];
+        let len = stream.read(&mut reply)?;
+        reply.truncate(len);
+        Ok(reply)
+    }
+}
+
+/// Mounts the pseudo filesystem `fstype` on `dir`, unless something already is
+fn mount_pseudo(fstype: &str, dir: &str) -> io::Result<()> {
+    match fs::create_dir(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let fstype = CString::new(fstype).unwrap();
+    let dir = CString::new(dir).unwrap();
+    let ret = unsafe {
+        libc::mount(
+            fstype.as_ptr(),
+            dir.as_ptr(),
+            fstype.as_ptr(),
+            0,
+            ptr::null(),
+        )
+    };
+    match check(ret) {
+        Err(e) if e.raw_os_error() == Some(libc::EBUSY) => Ok(()),
+        ret => ret.map(|_| ()),
+    }
+}
+
+/// Reads from `/dev/hwrng`, and returns the driver backing it
+fn read_hwrng() -> io::Result<String> {
+    mount_pseudo("devtmpfs", "/dev")?;
+    mount_pseudo("sysfs", "/sys")?;
+    let mut buf = [0; 16];
+    File::open("/dev/hwrng")?.read_exact(&mut buf)?;
+    let current = fs::read_to_string("/sys/class/misc/hw_random/rng_current")?;
+    Ok(current.trim().to_string())
+}
 
-use vsock::{VsockStream, VMADDR_CID_HOST};
 fn main() {
-    let mut s = VsockStream::connect_with_cid_port(VMADDR_CID_HOST, 1234).unwrap();
-    let buf = vec![0x41, 0x42, 0x43, 0x44, 0x45, 0xa];
-    s.write_all(&buf).unwrap();
+    // Arguments after `--` on the kernel cmdline are passed to init
+    match env::args().nth(1).as_deref() {
+        Some("cad") => {
+            // With ctrl-alt-del disabled the kernel sends SIGINT to init instead of rebooting
+            unsafe {
+                libc::signal(libc::SIGINT, on_sigint as libc::sighandler_t);
+                libc::reboot(libc::LINUX_REBOOT_CMD_CAD_OFF);
+            }
+            send(1234, b"READY\n");
+            while !GOT_CTRL_ALT_DEL.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            send(1234, b"CAD\n");
+        }
+        // One message per port, for tests of several host listeners
+        Some("ports") => {
+            send(1234, b"LOG\n");
+            send(1235, b"CONTROL\n");
+        }
+        // Ignores ctrl-alt-del and never exits, for tests of the host's fallback
+        Some("nocad") => {
+            // Init has no SIGINT handler, so the signal the kernel sends instead is ignored
+            unsafe {
+                libc::reboot(libc::LINUX_REBOOT_CMD_CAD_OFF);
+            }
+            send(1234, b"READY\n");
+            loop {
+                thread::sleep(Duration::from_secs(60));
+            }
+        }
+        // Writes a marker to the first disk, then waits for the host to stop the VM
+        Some("write") => {
+            let mut disk = OpenOptions::new().write(true).open("/dev/vda").unwrap();
+            disk.write_all(b"PERSISTED\n").unwrap();
+            disk.sync_data().unwrap();
+            send(1234, b"WRITTEN\n");
+            loop {
+                thread::sleep(Duration::from_secs(60));
+            }
+        }
+        // Sends the marker `write` left on the first disk
+        Some("read") => {
+            let mut buf = [0; 10];
+            let mut disk = OpenOptions::new().read(true).open("/dev/vda").unwrap();
+            disk.read_exact(&mut buf).unwrap();
+            send(1234, &buf);
+        }
+        // Echoes one line back to the first host connection to guest port 5000
+        Some("serve") => {
+            let listener = VsockListener::bind_with_cid_port(VMADDR_CID_ANY, 5000).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut line = Vec::new();
+            let mut byte = [0];
+            while byte[0] != b'\n' {
+                stream.read_exact(&mut byte).unwrap();
+                line.push(byte[0]);
+            }
+            stream.write_all(&line).unwrap();
+        }
+        // Sends 8 MiB on each of 4 concurrent connections, for throughput tests
+        Some("bulk") => {
+            let senders: Vec<_> = (0..4)
+                .map(|_| {
+                    thread::spawn(|| {
+                        let mut s =
+                            VsockStream::connect_with_cid_port(VMADDR_CID_HOST, 1234).unwrap();
+                        let chunk = [0x55; 64 * 1024];
+                        for _ in 0..128 {
+                            s.write_all(&chunk).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for sender in senders {
+                sender.join().unwrap();
+            }
+        }
+        // Fetches MMDS through eth0 and then eth1, and reports which of them reached it
+        Some("mmds") => {
+            let mut report = String::new();
+            for (iface, addr) in [("eth0", [169, 254, 0, 10]), ("eth1", [169, 254, 0, 11])] {
+                let result = match mmds_get(iface, addr) {
+                    Ok(reply) if reply.starts_with(b"HTTP/") => "reached".to_string(),
+                    Ok(reply) => format!("garbled {reply:?}"),
+                    Err(e) => format!("unreachable: {e}"),
+                };
+                report.push_str(&format!("{iface} {result}\n"));
+            }
+            send(1234, report.as_bytes());
+        }
+        // Reports whether the hardware RNG can be read, and which driver backs it
+        Some("hwrng") => {
+            let report = match read_hwrng() {
+                Ok(current) => format!("hwrng {current}\n"),
+                Err(e) => format!("no hwrng: {e}\n"),
+            };
+            send(1234, report.as_bytes());
+        }
+        // Never exits, for tests that stop the VM from the host
+        Some("hang") => loop {
+            thread::sleep(Duration::from_secs(60));
+        },
+        _ => send(1234, &[0x41, 0x42, 0x43, 0x44, 0x45, 0xa]),
+    }
 }